@@ -0,0 +1,281 @@
+//! Deciding whether to switch to a new branch, and what to call it.
+
+use std::{
+    fmt,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::{commit_message::CommitMessage, ticket};
+
+/// Branches a new branch is created from when `protected_branches` isn't set.
+pub const DEFAULT_PROTECTED_BRANCHES: [&str; 2] = ["main", "master"];
+
+/// Commit type used in branch names when the model didn't give one.
+const DEFAULT_TYPE: &str = "feature";
+/// When to switch to a new branch before committing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BranchMode {
+    /// Always commit on the current branch.
+    Never,
+    /// Only branch off `main`, `master` or another protected branch.
+    #[default]
+    Protected,
+    /// Branch off whatever branch is checked out.
+    Always,
+}
+
+impl fmt::Display for BranchMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BranchMode::Never => "never",
+            BranchMode::Protected => "protected",
+            BranchMode::Always => "always",
+        })
+    }
+}
+
+/// What to do when the proposed branch already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Collision {
+    /// Append `-2`, `-3`, ... until the name is free.
+    #[default]
+    Suffix,
+    /// Switch to the existing branch.
+    Reuse,
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Collision::Suffix => "suffix",
+            Collision::Reuse => "reuse",
+        })
+    }
+}
+
+/// Whether committing with `current` checked out should be avoided. A
+/// detached HEAD isn't protected, but committing there loses the commit as
+/// soon as something else is checked out.
+pub fn is_protected(current: Option<&str>, protected: &[String]) -> bool {
+    current.is_none_or(|current| protected.iter().any(|branch| branch == current))
+}
+
+/// Whether `mode` calls for a new branch when `current` is checked out.
+pub fn should_create(mode: BranchMode, current: Option<&str>, protected: &[String]) -> bool {
+    match mode {
+        BranchMode::Never => false,
+        BranchMode::Always => true,
+        BranchMode::Protected => is_protected(current, protected),
+    }
+}
+
+/// Lowercase `text` and replace everything but letters and digits with
+/// single dashes.
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Fill in a branch name template.
+///
+/// Supported placeholders are `{type}` (the commit type, or the prefix of the
+/// model's suggestion), `{ticket}`, `{slug}` (the rest of the model's
+/// suggestion, or else the subject) and `{branch}` (the suggestion as is).
+/// Separators left dangling by an empty placeholder are dropped.
+pub fn render(template: &str, message: &CommitMessage, ticket: Option<&str>) -> String {
+    let (prefix, rest) = match message.branch.rsplit_once('/') {
+        Some((prefix, rest)) => (Some(prefix), rest),
+        None => (None, message.branch.as_str()),
+    };
+    let kind = message.kind.as_deref().or(prefix).unwrap_or(DEFAULT_TYPE);
+    let slug = match slugify(rest) {
+        slug if slug.is_empty() => slugify(&message.subject),
+        slug => slug,
+    };
+
+    let name = template
+        .replace("{type}", &slugify(kind))
+        .replace("{ticket}", ticket.unwrap_or_default())
+        .replace("{slug}", &slug)
+        .replace("{branch}", &message.branch);
+
+    let mut cleaned = String::new();
+    for c in name.chars() {
+        let previous = cleaned.chars().last();
+        match (previous, c) {
+            (None, '-' | '/') => {}
+            (Some('-' | '/'), '-') | (Some('/'), '/') => {}
+            (Some('-'), '/') => {
+                cleaned.pop();
+                cleaned.push('/');
+            }
+            _ => cleaned.push(c),
+        }
+    }
+    cleaned.trim_end_matches(['-', '/']).to_string()
+}
+
+/// Whether git accepts `name` as a branch name.
+fn is_valid(name: &str) -> bool {
+    Command::new("git")
+        .args(["check-ref-format", "--branch", name])
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// `name` if git accepts it, or else one made from the subject, or as a last
+/// resort `happycommit/<ticket>` or `happycommit/<seconds since the epoch>`.
+pub fn valid_name(name: &str, message: &CommitMessage, ticket: Option<&str>) -> String {
+    let rendered = render(ticket::BRANCH_TEMPLATE, message, ticket);
+    let last_resort = match ticket {
+        Some(ticket) => format!("happycommit/{}", ticket),
+        None => format!("happycommit/{}", timestamp()),
+    };
+    [name.to_string(), rendered, last_resort]
+        .into_iter()
+        .find(|candidate| !candidate.is_empty() && is_valid(candidate))
+        .unwrap_or_else(|| format!("happycommit/{}", timestamp()))
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn exists(name: &str) -> bool {
+    Command::new("git")
+        .args(["rev-parse", "--verify", "--quiet"])
+        .arg(format!("refs/heads/{}", name))
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Switch to a new branch called `name`, or to an existing one when
+/// `collision` says to reuse it. Returns the name of the branch switched to.
+pub fn switch(name: &str, collision: Collision, dry_run: bool) -> Result<String> {
+    if !is_valid(name) {
+        return Err(anyhow!("\"{}\" is not a valid branch name", name));
+    }
+
+    let (name, create) = match (exists(name), collision) {
+        (false, _) => (name.to_string(), true),
+        (true, Collision::Reuse) => (name.to_string(), false),
+        (true, Collision::Suffix) => {
+            let name = (2..)
+                .map(|n| format!("{}-{}", name, n))
+                .find(|candidate| !exists(candidate))
+                .unwrap();
+            (name, true)
+        }
+    };
+
+    if dry_run {
+        println!("Would set Branch: {}", name);
+        return Ok(name);
+    }
+
+    eprintln!("Setting Branch: {}", name);
+    let mut command = Command::new("git");
+    command.arg("switch");
+    if create {
+        command.arg("-c");
+    }
+    let output = command.arg(&name).output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git switch failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(branch: &str, kind: Option<&str>) -> CommitMessage {
+        CommitMessage {
+            branch: branch.to_string(),
+            subject: "Add retries to the uploader".to_string(),
+            kind: kind.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_should_create() {
+        let protected = vec!["main".to_string(), "release".to_string()];
+        assert!(should_create(
+            BranchMode::Protected,
+            Some("release"),
+            &protected
+        ));
+        assert!(!should_create(
+            BranchMode::Protected,
+            Some("feature/x"),
+            &protected
+        ));
+        assert!(should_create(BranchMode::Protected, None, &protected));
+        assert!(should_create(
+            BranchMode::Always,
+            Some("feature/x"),
+            &protected
+        ));
+        assert!(!should_create(BranchMode::Never, Some("main"), &protected));
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert_eq!(
+            valid_name("bugfix/retries", &message("", None), None),
+            "bugfix/retries"
+        );
+        assert_eq!(
+            valid_name("bugfix/bad..name", &message("bugfix/bad..name", None), None),
+            "bugfix/bad-name"
+        );
+        let mut untitled = message("", None);
+        untitled.subject = "..".to_string();
+        assert_eq!(
+            valid_name("", &untitled, Some("PROJ-12")),
+            "feature/PROJ-12"
+        );
+    }
+
+    #[test]
+    fn test_render_template() {
+        let template = "{type}/{ticket}-{slug}";
+        assert_eq!(
+            render(
+                template,
+                &message("feature/Upload Retries", Some("feat")),
+                Some("PROJ-12")
+            ),
+            "feat/PROJ-12-upload-retries"
+        );
+        assert_eq!(
+            render(template, &message("bugfix/upload-retries", None), None),
+            "bugfix/upload-retries"
+        );
+        assert_eq!(
+            render(template, &message("", None), None),
+            "feature/add-retries-to-the-uploader"
+        );
+    }
+}
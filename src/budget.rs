@@ -0,0 +1,116 @@
+//! Token budgeting for diff slices.
+//!
+//! Every slice is sent together with the system prompt, and the model needs
+//! room to answer. The budget works out how much of the model's context
+//! window is left for the diff itself.
+
+use anyhow::{anyhow, Result};
+use tiktoken_rs::{cl100k_base, get_bpe_from_model, model::get_context_size, CoreBPE};
+
+/// Tokens reserved for the model's answer when `max_tokens` isn't configured.
+pub const DEFAULT_RESPONSE_TOKENS: usize = 500;
+
+/// Upper bound on how many tokens consecutive slices share.
+const MAX_OVERLAP_TOKENS: usize = 200;
+
+/// Framing tokens chat models add around each message.
+const TOKENS_PER_MESSAGE: usize = 4;
+
+/// Framing tokens that prime the assistant's reply.
+const TOKENS_PER_REPLY: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenBudget {
+    /// Tokens the model can handle, prompt and response combined.
+    pub context_window: usize,
+    /// Tokens kept free for the response.
+    pub response_tokens: usize,
+}
+
+impl TokenBudget {
+    /// Budget for `model`, unless the config overrides its context window
+    /// (useful for local models tiktoken has never heard of).
+    pub fn for_model(model: &str, context_window: Option<usize>, max_tokens: Option<u16>) -> Self {
+        TokenBudget {
+            context_window: context_window.unwrap_or_else(|| get_context_size(model)),
+            response_tokens: max_tokens.map_or(DEFAULT_RESPONSE_TOKENS, usize::from),
+        }
+    }
+
+    /// How many diff tokens fit in one request.
+    ///
+    /// `fixed_prompts` are the messages sent alongside every slice; room for
+    /// the response is reserved on top of them.
+    pub fn code_tokens(&self, tokenizer: &CoreBPE, fixed_prompts: &[&str]) -> Result<usize> {
+        let prompt_tokens: usize = fixed_prompts
+            .iter()
+            .map(|prompt| tokenizer.encode_with_special_tokens(prompt).len() + TOKENS_PER_MESSAGE)
+            .sum();
+        let reserved = prompt_tokens + self.response_tokens + TOKENS_PER_MESSAGE + TOKENS_PER_REPLY;
+
+        self.context_window
+            .checked_sub(reserved)
+            .filter(|code_tokens| *code_tokens > 0)
+            .ok_or_else(|| {
+                anyhow!(
+                    "A context window of {} tokens is too small: the prompt and response alone need {}",
+                    self.context_window,
+                    reserved
+                )
+            })
+    }
+
+    /// How many tokens consecutive slices should share, so a change that
+    /// straddles a slice boundary is seen whole at least once.
+    pub fn overlap(code_tokens: usize) -> usize {
+        (code_tokens / 10).min(MAX_OVERLAP_TOKENS)
+    }
+
+    /// How many of the `code_tokens` the overview and notable files sent
+    /// in front of every slice may take: a quarter, leaving the rest to the
+    /// diff.
+    pub fn preamble_tokens(code_tokens: usize) -> usize {
+        code_tokens / 4
+    }
+}
+
+/// The tokenizer `model` uses, falling back to `cl100k_base` for models
+/// tiktoken doesn't know about, such as local ones.
+pub fn tokenizer_for_model(model: &str) -> CoreBPE {
+    get_bpe_from_model(model).unwrap_or_else(|_| cl100k_base().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_larger_context_windows_fit_more_code() {
+        let tokenizer = cl100k_base().unwrap();
+        let small = TokenBudget::for_model("local", Some(4096), None);
+        let large = TokenBudget::for_model("local", Some(32768), None);
+
+        let small_tokens = small.code_tokens(&tokenizer, &["prompt"]).unwrap();
+        let large_tokens = large.code_tokens(&tokenizer, &["prompt"]).unwrap();
+        assert!(small_tokens < 4096 - DEFAULT_RESPONSE_TOKENS);
+        assert_eq!(large_tokens - small_tokens, 32768 - 4096);
+    }
+
+    #[test]
+    fn test_reserves_prompts_and_response() {
+        let tokenizer = cl100k_base().unwrap();
+        let budget = TokenBudget::for_model("local", Some(4096), Some(100));
+        let prompt = tokenizer.encode_with_special_tokens("prompt").len();
+        assert_eq!(
+            budget.code_tokens(&tokenizer, &["prompt"]).unwrap(),
+            4096 - (prompt + TOKENS_PER_MESSAGE) - TOKENS_PER_MESSAGE - 100 - TOKENS_PER_REPLY
+        );
+    }
+
+    #[test]
+    fn test_context_window_too_small() {
+        let tokenizer = cl100k_base().unwrap();
+        let budget = TokenBudget::for_model("local", Some(512), Some(512));
+        assert!(budget.code_tokens(&tokenizer, &["prompt"]).is_err());
+    }
+}
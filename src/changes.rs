@@ -0,0 +1,94 @@
+//! Which changes the message is written for. They are read through
+//! [`crate::repo`], but staged for `--all` by git itself.
+
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::cli::Options;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// The staged changes, the default.
+    Staged,
+    /// The staged changes together with the last commit, since an amended
+    /// commit replaces it.
+    Amend,
+    /// Staged and unstaged changes to tracked files (`--all`).
+    All,
+    /// The commits since the current branch forked from a revision, plus
+    /// the staged changes (`--from`).
+    Since(String),
+    /// An existing commit (`--commit`).
+    Commit(String),
+}
+
+impl Source {
+    pub fn from_options(options: &Options) -> Source {
+        if let Some(rev) = &options.from {
+            Source::Since(rev.clone())
+        } else if let Some(sha) = &options.commit {
+            Source::Commit(sha.clone())
+        } else if options.all {
+            Source::All
+        } else if options.amend {
+            Source::Amend
+        } else {
+            Source::Staged
+        }
+    }
+
+    /// Whether committing with a message for these changes makes sense.
+    /// Messages for a range or an existing commit describe more, or other,
+    /// changes than the next commit would contain.
+    pub fn can_commit(&self) -> bool {
+        matches!(self, Source::Staged | Source::Amend | Source::All)
+    }
+}
+
+/// Stage the changes to tracked files that `--all` wrote the message for.
+pub fn stage_all() -> Result<()> {
+    let status = Command::new("git")
+        .args(["add", "--update", "--", "."])
+        .status()
+        .context("Failed to run git")?;
+    if !status.success() {
+        return Err(anyhow!("git add failed to stage the changes"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_from_options() {
+        let options = |from: Option<&str>, commit: Option<&str>, all, amend| Options {
+            from: from.map(String::from),
+            commit: commit.map(String::from),
+            all,
+            amend,
+            ..Default::default()
+        };
+        assert_eq!(
+            Source::from_options(&options(None, None, false, false)),
+            Source::Staged
+        );
+        assert_eq!(
+            Source::from_options(&options(None, None, false, true)),
+            Source::Amend
+        );
+        assert_eq!(
+            Source::from_options(&options(None, None, true, false)),
+            Source::All
+        );
+        let since = Source::from_options(&options(Some("origin/main"), None, false, false));
+        assert_eq!(since, Source::Since("origin/main".to_string()));
+        assert!(!since.can_commit());
+        assert_eq!(
+            Source::from_options(&options(None, Some("abc123"), false, false)),
+            Source::Commit("abc123".to_string())
+        );
+    }
+}
@@ -0,0 +1,191 @@
+//! Command line arguments.
+
+use std::{
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+
+/// Generate meaningful commit messages (and branch names) from your staged changes.
+#[derive(Debug, Parser)]
+#[command(name = "happycommit", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub options: Options,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Write a commit message for the staged changes and commit them (the default)
+    Commit,
+    /// Print a commit message for the staged changes without touching the repository
+    ///
+    /// Only the message goes to stdout; progress is written to stderr, so the
+    /// output can be piped into scripts and editors.
+    Message {
+        /// Print the message as a JSON object with branch, subject, body and type
+        #[arg(long)]
+        json: bool,
+    },
+    /// Switch to a new branch named after the staged changes, without committing
+    Branch,
+    /// Show where the config file lives and the settings in effect
+    Config,
+    /// Register the `git commit-gpt` alias and store your API key
+    Setup,
+    /// Check the config, API key, endpoint, model, git and repository
+    Doctor,
+    /// Manage the prepare-commit-msg hook of the current repository
+    Hook {
+        #[command(subcommand)]
+        action: HookAction,
+    },
+    /// Check a commit message file against the subject and body rules
+    ///
+    /// Takes the same argument as a commit-msg hook, so it can be called
+    /// from one. Exits with 1 if any violations are left.
+    Lint {
+        /// The commit message file, e.g. .git/COMMIT_EDITMSG
+        file: PathBuf,
+        /// Fix what can be fixed in place, such as rewrapping the body
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Print a shell completion script
+    Completions {
+        /// The shell to generate completions for
+        shell: Shell,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum HookAction {
+    /// Fill in the message whenever you run a plain `git commit`
+    Install {
+        /// Replace an existing prepare-commit-msg hook
+        #[arg(long)]
+        force: bool,
+    },
+    /// Remove the hook installed by `happycommit hook install`
+    Uninstall,
+    /// Called by git with the commit message file and where the message came from
+    #[command(name = "prepare-commit-msg", hide = true)]
+    PrepareCommitMsg {
+        file: PathBuf,
+        source: Option<String>,
+        sha: Option<String>,
+    },
+}
+
+/// Flags shared by every subcommand.
+#[derive(Clone, Debug, Default, Args)]
+pub struct Options {
+    /// Model to use instead of the one in the config file
+    #[arg(long, global = true)]
+    pub model: Option<String>,
+
+    /// Commit on the current branch instead of switching to a new one
+    #[arg(long, global = true)]
+    pub no_branch: bool,
+
+    /// Generate the message and show what would be done, without touching the repository
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Commit without opening the message in an editor first
+    #[arg(short, long, global = true)]
+    pub yes: bool,
+
+    /// Rewrite the message of the last commit, taking its changes into account
+    #[arg(long, global = true)]
+    pub amend: bool,
+
+    /// Include changes that aren't staged yet, and stage them before committing
+    #[arg(short, long, global = true, conflicts_with_all = ["amend", "from", "commit"])]
+    pub all: bool,
+
+    /// Describe the commits since the branch forked from REV plus the staged changes, e.g. origin/main
+    #[arg(long, value_name = "REV", global = true, conflicts_with_all = ["amend", "commit"])]
+    pub from: Option<String>,
+
+    /// Describe an existing commit instead of the staged changes
+    #[arg(long, value_name = "SHA", global = true, conflicts_with = "amend")]
+    pub commit: Option<String>,
+
+    /// Print the commit message written for each slice of a large diff
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+}
+
+/// Write the completion script for `shell` to stdout.
+pub fn print_completions(shell: Shell) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+}
+
+/// Ask a yes/no question on stderr, defaulting to no.
+pub fn confirm(question: &str) -> anyhow::Result<bool> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commit_is_the_default() {
+        let cli = Cli::try_parse_from(["happycommit", "--no-branch", "--model", "gpt-4"]).unwrap();
+        assert!(cli.command.is_none());
+        assert!(cli.options.no_branch);
+        assert_eq!(cli.options.model.as_deref(), Some("gpt-4"));
+    }
+
+    #[test]
+    fn test_flags_after_subcommand() {
+        let cli = Cli::try_parse_from(["happycommit", "message", "--json", "--verbose"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Message { json: true })));
+        assert!(cli.options.verbose);
+    }
+
+    #[test]
+    fn test_hook_arguments_from_git() {
+        let cli =
+            Cli::try_parse_from(["happycommit", "hook", "prepare-commit-msg", "MSG", "merge"])
+                .unwrap();
+        match cli.command {
+            Some(Command::Hook {
+                action: HookAction::PrepareCommitMsg { file, source, sha },
+            }) => {
+                assert_eq!(file, PathBuf::from("MSG"));
+                assert_eq!(source.as_deref(), Some("merge"));
+                assert_eq!(sha, None);
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_diff_ranges_conflict() {
+        let cli = Cli::try_parse_from(["happycommit", "message", "--from", "origin/main"]).unwrap();
+        assert_eq!(cli.options.from.as_deref(), Some("origin/main"));
+        assert!(Cli::try_parse_from(["happycommit", "--all", "--amend"]).is_err());
+        assert!(
+            Cli::try_parse_from(["happycommit", "--from", "main", "--commit", "HEAD"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+}
@@ -0,0 +1,529 @@
+//! Pulling the branch, subject and body out of the model's answer.
+//!
+//! The prompt asks for
+//!
+//! ```text
+//! Branch: <branch>
+//! ~~~~~~~~~~
+//! Subject: <subject>
+//! ~~~~~~~~~~
+//! Body: <body>
+//! ```
+//!
+//! Models mostly stick to it, give or take separators, quoting, markdown and
+//! code fences, so [`CommitMessage::parse`] reads it locally. Only when that
+//! fails do we ask the model to pick its own answer apart with [`query`].
+//!
+//! In JSON mode the answer is validated with [`CommitMessage::from_json`]
+//! instead. Either way, [`with_retries`] asks the model to correct answers
+//! that can't be read or break the house rules, such as Conventional Commits.
+
+use std::fmt;
+
+use anyhow::{anyhow, Context, Result};
+use async_openai::types::Role;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    provider::{CompletionProvider, ResponseFormat},
+    send_to_provider, ChatMessage, Output,
+};
+
+/// How the subject line is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Style {
+    /// A plain imperative sentence.
+    #[default]
+    Plain,
+    /// A Conventional Commits `type(scope)!: description` header.
+    Conventional,
+}
+
+impl fmt::Display for Style {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Style::Plain => "plain",
+            Style::Conventional => "conventional",
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CommitMessage {
+    pub branch: String,
+    pub subject: String,
+    pub body: String,
+    /// Kind of change, such as `feat` or `fix`. Only known in JSON mode.
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub scope: Option<String>,
+    pub breaking: bool,
+}
+
+/// The object the model is asked for in JSON mode. Everything but `scope`
+/// is required.
+#[derive(Deserialize)]
+struct JsonCommitMessage {
+    branch: String,
+    subject: String,
+    body: String,
+    #[serde(rename = "type")]
+    kind: String,
+    scope: Option<String>,
+    breaking: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Branch,
+    Subject,
+    Body,
+}
+
+impl CommitMessage {
+    /// Parse the `Branch: ... Subject: ... Body: ...` format.
+    ///
+    /// Returns `None` unless at least a branch and a subject were found.
+    pub fn parse(text: &str) -> Option<CommitMessage> {
+        let mut branch = String::new();
+        let mut subject = String::new();
+        let mut body = String::new();
+        let mut field = None;
+
+        // separators sometimes end up on the same line as the fields
+        let text = text.replace("~~~~~~~~~~", "\n");
+
+        for line in text.lines() {
+            let trimmed = line.trim();
+            if is_separator(trimmed) {
+                continue;
+            }
+
+            let (current, rest) = match split_label(trimmed) {
+                Some((label, rest)) => {
+                    field = Some(label);
+                    (label, rest)
+                }
+                None => match field {
+                    Some(current) => (current, line.trim_end()),
+                    None => continue,
+                },
+            };
+
+            let target = match current {
+                Field::Branch => &mut branch,
+                Field::Subject => &mut subject,
+                Field::Body => &mut body,
+            };
+            if !target.is_empty() || !rest.is_empty() {
+                target.push_str(rest);
+                target.push('\n');
+            }
+        }
+
+        let message = CommitMessage {
+            branch: unquote(branch.trim()).to_string(),
+            subject: unquote(subject.trim()).to_string(),
+            body: unquote(body.trim()).to_string(),
+            ..CommitMessage::default()
+        };
+
+        if message.branch.is_empty() || message.subject.is_empty() {
+            return None;
+        }
+        Some(message)
+    }
+
+    /// Parse and validate the JSON format, ignoring any prose or code fences
+    /// around the object.
+    pub fn from_json(text: &str) -> Result<CommitMessage> {
+        let json = match (text.find('{'), text.rfind('}')) {
+            (Some(start), Some(end)) if start < end => &text[start..=end],
+            _ => return Err(anyhow!("no JSON object found")),
+        };
+        let message: JsonCommitMessage = serde_json::from_str(json)?;
+
+        let branch = message.branch.trim();
+        let subject = message.subject.trim();
+        let kind = message.kind.trim();
+        if branch.is_empty() || branch.contains(char::is_whitespace) {
+            return Err(anyhow!("`branch` must be a non-empty name without spaces"));
+        }
+        if subject.is_empty() || subject.contains('\n') {
+            return Err(anyhow!("`subject` must be a single non-empty line"));
+        }
+        if kind.is_empty() || !kind.chars().all(|c| c.is_ascii_lowercase()) {
+            return Err(anyhow!(
+                "`type` must be a lowercase word such as \"feat\" or \"fix\""
+            ));
+        }
+
+        Ok(CommitMessage {
+            branch: branch.to_string(),
+            subject: subject.to_string(),
+            body: message.body.trim().to_string(),
+            kind: Some(kind.to_string()),
+            scope: message
+                .scope
+                .map(|scope| scope.trim().to_string())
+                .filter(|scope| !scope.is_empty()),
+            breaking: message.breaking,
+        })
+    }
+
+    /// The message in the `Branch: ... Subject: ... Body: ...` format the
+    /// model is asked for, to hand a corrected answer back to it.
+    pub fn to_text(&self) -> String {
+        format!(
+            "Branch: {}\n~~~~~~~~~~\nSubject: {}\n~~~~~~~~~~\nBody: {}",
+            self.branch, self.subject, self.body
+        )
+    }
+
+    /// The message as git should get it: the subject, a blank line, the body,
+    /// and `footer` as a separate trailer block.
+    pub fn to_git_message(&self, footer: Option<&str>) -> String {
+        let mut paragraphs = vec![self.subject.trim().to_string()];
+        let body = tidy(&self.body);
+        if !body.is_empty() {
+            paragraphs.push(body);
+        }
+        if let Some(footer) = footer.map(tidy).filter(|footer| !footer.is_empty()) {
+            paragraphs.push(footer);
+        }
+        paragraphs.join("\n\n") + "\n"
+    }
+}
+
+/// Drop trailing whitespace, the indentation every line shares, and runs of
+/// blank lines, which models like to pad their answers with.
+fn tidy(text: &str) -> String {
+    let indent = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.chars().take_while(|c| c.is_whitespace()).count())
+        .min()
+        .unwrap_or(0);
+
+    let mut tidied = String::new();
+    for line in text.lines() {
+        let line: String = line.chars().skip(indent).collect();
+        let line = line.trim_end();
+        if line.is_empty() && (tidied.is_empty() || tidied.ends_with("\n\n")) {
+            continue;
+        }
+        tidied.push_str(line);
+        tidied.push('\n');
+    }
+    tidied.trim_end().to_string()
+}
+
+/// Lines made up of nothing but separator characters, and code fences.
+fn is_separator(line: &str) -> bool {
+    line.starts_with("```") || (line.len() >= 3 && line.chars().all(|c| c == '~' || c == '='))
+}
+
+/// Recognise `Branch:`, `**Subject:**`, `body :` and the like at the start of a line.
+fn split_label(line: &str) -> Option<(Field, &str)> {
+    let (label, rest) = line.split_once(':')?;
+    let field = match label.trim_matches(|c: char| c == '*' || c == '#' || c.is_whitespace()) {
+        label if label.eq_ignore_ascii_case("branch") => Field::Branch,
+        label if label.eq_ignore_ascii_case("subject") => Field::Subject,
+        label if label.eq_ignore_ascii_case("body") => Field::Body,
+        _ => return None,
+    };
+    Some((field, rest.trim_start_matches('*').trim()))
+}
+
+/// Strip one pair of matching quotes or backticks around `text`.
+fn unquote(text: &str) -> &str {
+    for quote in ['"', '\'', '`'] {
+        if text.len() >= 2 && text.starts_with(quote) && text.ends_with(quote) {
+            return text[1..text.len() - 1].trim();
+        }
+    }
+    text
+}
+
+const COMMIT_QUERY_MESSAGE: &str = "The CommitMesssageProvider will provide text using the following format, then request either \"Branch\", \"Subject\", or \"Body\"
+You will provide back the user the exact contents of what is requested. This will be done without any additiona prose or formatting.
+Here is the format that the user will provide (note, the user may not follow this format exactly, you will need to interpolate the text into the correct place):
+```
+Branch: <suggested branch name>
+~~~~~~~~~~
+Subject: <subject>
+~~~~~~~~~~
+Body: <body>
+```
+
+Example 1:
+```
+Provide Branch for:
+
+Branch: feature/1234
+~~~~~~~~~~
+Subject: Add a new feature
+~~~~~~~~~~
+Body: This is a new feature that does something really cool.
+- cool thing 1
+- cool thing 2
+
+Good Output: feature/1234
+Bad Output: \"feature/1234\"
+Bad Output: \"Branch: feature/1234\"
+```
+
+Example 2:
+```
+Provide Subject for:
+
+Branch: feature/1234
+~~~~~~~~~~
+Subject: Add a new feature
+~~~~~~~~~~
+Body: This is a new feature that does something really cool.
+- cool thing 1
+- cool thing 2
+
+Good Output: Add a new feature
+Bad Output: \"Add a new feature\"
+Bad Output: \"Subject: Add a new feature\"
+```
+
+Example 3:
+```
+Provide Body for:
+
+Branch: feature/1234
+~~~~~~~~~~
+Subject: Add a new feature
+~~~~~~~~~~
+Body: This is a new feature that does something really cool.
+- cool thing 1
+- cool thing 2
+
+Good Output: This is a new feature that does something really cool.
+- cool thing 1
+- cool thing 2
+Bad Output: \"This is a new feature that does something really cool.\"
+Bad Output: \"Body: This is a new feature that does something really cool.\"
+```";
+
+/// Ask the model to extract each field from `text` for us, one request per
+/// field. Only used when [`CommitMessage::parse`] can't make sense of it.
+pub async fn query(provider: &dyn CompletionProvider, text: &str) -> Result<CommitMessage> {
+    let create_messages = |query: &str| -> Vec<ChatMessage> {
+        vec![
+            (
+                "CommitQueryBot".to_string(),
+                Role::System,
+                COMMIT_QUERY_MESSAGE.to_string(),
+            ),
+            (
+                "CommitMessageProvider".to_string(),
+                Role::Assistant,
+                text.to_string(),
+            ),
+            (
+                "User".to_string(),
+                Role::User,
+                format!("Provide {} for commit message ^", query),
+            ),
+        ]
+    };
+
+    let ask = |query| {
+        send_to_provider(
+            provider,
+            create_messages(query),
+            ResponseFormat::Text,
+            Output::Stream,
+        )
+    };
+    let branch = ask("Branch").await?;
+    let subject = ask("Subject").await?;
+    let body = ask("Body").await?;
+
+    Ok(CommitMessage {
+        branch: branch.trim().to_string(),
+        subject: subject.trim().to_string(),
+        body: body.trim().to_string(),
+        ..CommitMessage::default()
+    })
+}
+
+/// Read the model's answer with `validate`, sending it back for correction
+/// up to `max_retries` times if it's rejected.
+///
+/// `system_message` is the prompt the answer was written for, so the model
+/// sees the format and rules it was supposed to follow. `validate` is also
+/// given how many retries were spent, so it can accept what it would
+/// otherwise reject once none are left.
+pub async fn with_retries(
+    provider: &dyn CompletionProvider,
+    system_message: &str,
+    format: ResponseFormat,
+    text: &str,
+    max_retries: usize,
+    validate: impl Fn(&str, usize) -> Result<CommitMessage>,
+) -> Result<CommitMessage> {
+    let mut text = text.to_string();
+    let mut attempt = 0;
+
+    loop {
+        let error = match validate(&text, attempt) {
+            Ok(message) => return Ok(message),
+            Err(error) if attempt < max_retries => error,
+            Err(error) => {
+                return Err(error).context(format!(
+                    "The commit message still wasn't valid after {} retries",
+                    max_retries
+                ))
+            }
+        };
+        attempt += 1;
+        eprintln!(
+            "The commit message isn't valid ({}), asking for a corrected one ({} of {})...",
+            error, attempt, max_retries
+        );
+
+        let correction = match format {
+            ResponseFormat::Text => {
+                "Respond again with only the corrected commit message, in the same format."
+            }
+            ResponseFormat::Json => "Respond again with only the corrected JSON object.",
+        };
+        let messages = vec![
+            (
+                "GitCommitBot".to_string(),
+                Role::System,
+                system_message.to_string(),
+            ),
+            ("GitCommitBot".to_string(), Role::Assistant, text),
+            (
+                "User".to_string(),
+                Role::User,
+                format!("That answer is invalid: {}. {}", error, correction),
+            ),
+        ];
+        text = send_to_provider(provider, messages, format, Output::Stream).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_format() {
+        let message = CommitMessage::parse(
+            "Branch: feature/cool-thing
+~~~~~~~~~~
+Subject: Add a cool thing
+~~~~~~~~~~
+Body: This is a new feature that does something really cool.
+
+- cool thing 1
+- cool thing 2
+",
+        )
+        .unwrap();
+
+        assert_eq!(message.branch, "feature/cool-thing");
+        assert_eq!(message.subject, "Add a cool thing");
+        assert_eq!(
+            message.body,
+            "This is a new feature that does something really cool.\n\n- cool thing 1\n- cool thing 2"
+        );
+    }
+
+    #[test]
+    fn test_parse_is_tolerant() {
+        let message = CommitMessage::parse(
+            "Sure! Here is your commit message:
+```
+**Branch:** `fix/typo`
+Subject: \"Fix typo in README\"
+body:
+Corrects a typo.
+```",
+        )
+        .unwrap();
+
+        assert_eq!(message.branch, "fix/typo");
+        assert_eq!(message.subject, "Fix typo in README");
+        assert_eq!(message.body, "Corrects a typo.");
+
+        let message =
+            CommitMessage::parse("Branch: a ~~~~~~~~~~ Subject: b ~~~~~~~~~~ Body: c").unwrap();
+        assert_eq!(
+            (
+                message.branch.as_str(),
+                message.subject.as_str(),
+                message.body.as_str()
+            ),
+            ("a", "b", "c")
+        );
+    }
+
+    #[test]
+    fn test_from_json() {
+        let message = CommitMessage::from_json(
+            r#"```json
+{"branch": "feat/json-mode", "subject": "Add JSON output mode", "body": "Asks for a JSON object.", "type": "feat", "scope": "cli", "breaking": false}
+```"#,
+        )
+        .unwrap();
+
+        assert_eq!(message.branch, "feat/json-mode");
+        assert_eq!(message.subject, "Add JSON output mode");
+        assert_eq!(message.kind.as_deref(), Some("feat"));
+        assert_eq!(message.scope.as_deref(), Some("cli"));
+        assert!(!message.breaking);
+    }
+
+    #[test]
+    fn test_from_json_rejects_schema_violations() {
+        // missing `type`
+        assert!(CommitMessage::from_json(
+            r#"{"branch": "a", "subject": "b", "body": "c", "scope": null, "breaking": false}"#
+        )
+        .is_err());
+        // branch with spaces
+        assert!(CommitMessage::from_json(
+            r#"{"branch": "a b", "subject": "b", "body": "c", "type": "fix", "scope": null, "breaking": false}"#
+        )
+        .is_err());
+        assert!(CommitMessage::from_json("Branch: a\nSubject: b").is_err());
+    }
+
+    #[test]
+    fn test_git_message_whitespace_and_footer() {
+        let message = CommitMessage {
+            subject: " Add retries ".to_string(),
+            body: "\n    The uploader gave up too early.\n\n\n    - retry three times  \n"
+                .to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            message.to_git_message(Some("Generated-by: happycommit")),
+            "Add retries\n\nThe uploader gave up too early.\n\n- retry three times\n\nGenerated-by: happycommit\n"
+        );
+
+        let message = CommitMessage {
+            subject: "Fix typo".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(message.to_git_message(Some("")), "Fix typo\n");
+    }
+
+    #[test]
+    fn test_parse_fails_without_subject() {
+        assert_eq!(
+            CommitMessage::parse("Let me help you write a good commit message."),
+            None
+        );
+        assert_eq!(CommitMessage::parse("Branch: fix/typo"), None);
+    }
+}
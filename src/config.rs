@@ -0,0 +1,286 @@
+//! Settings read from `~/.happycommit/config.toml`.
+
+use std::{fmt, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+    branch::{BranchMode, Collision, DEFAULT_PROTECTED_BRANCHES},
+    commit_message::Style,
+    conventional, history,
+    prompt::Conventions,
+    provider::{ProviderKind, ResponseFormat},
+    redact::{Redactor, SecretPolicy},
+    ticket::{self, Placement},
+};
+
+/// Slices summarized at the same time when `concurrency` isn't set.
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// The parsed contents of `~/.happycommit/config.toml`.
+///
+/// Every key is optional; a missing file is the same as an empty one.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Key for the OpenAI API. Falls back to `OPENAI_API_KEY` in `.env`.
+    #[serde(rename = "OPENAI_API_KEY")]
+    pub openai_api_key: Option<String>,
+    /// Which backend to send diffs to.
+    pub provider: ProviderKind,
+    /// Model to request completions from. Defaults depend on the provider.
+    pub model: Option<String>,
+    /// Base URL of an OpenAI-compatible API, e.g. an internal gateway, or
+    /// of an Azure OpenAI resource when `api_version` is set.
+    pub api_base: Option<String>,
+    /// Azure OpenAI API version, e.g. `2024-02-01`. Setting it sends requests
+    /// the way Azure expects them.
+    pub api_version: Option<String>,
+    /// Azure OpenAI deployment to send requests to. Defaults to `model`.
+    pub deployment: Option<String>,
+    /// Sampling temperature. Uses the backend's default when unset.
+    pub temperature: Option<f32>,
+    /// Upper bound on the number of tokens generated per completion.
+    pub max_tokens: Option<u16>,
+    /// Context window of the model, for models tiktoken doesn't know about.
+    pub context_window: Option<usize>,
+    /// OpenAI organization ID to bill requests to.
+    pub organization: Option<String>,
+    /// How long a single request may take, in seconds.
+    pub timeout_seconds: Option<u64>,
+    /// How many slices of a large diff are summarized at the same time.
+    pub concurrency: Option<usize>,
+    /// Whether to ask the model for free text or for a JSON object.
+    pub response_format: ResponseFormat,
+    /// When to switch to a new branch before committing.
+    pub create_branch: BranchMode,
+    /// Branches that are never committed to directly when `create_branch`
+    /// is `protected`. Defaults to `main` and `master`.
+    pub protected_branches: Option<Vec<String>>,
+    /// How to name new branches, e.g. `{type}/{ticket}-{slug}`. Uses the
+    /// model's suggestion as is when unset.
+    pub branch_template: Option<String>,
+    /// What to do when the new branch already exists.
+    pub branch_collision: Collision,
+    /// Whether subjects are plain sentences or Conventional Commits headers.
+    pub style: Style,
+    /// Types allowed in Conventional Commits headers. Defaults to the
+    /// Angular set: feat, fix, docs, style, refactor, perf, test, build, ci,
+    /// chore and revert.
+    pub conventional_types: Option<Vec<String>>,
+    /// How many recent commits to learn the repository's style from. `0`
+    /// turns this off.
+    pub history_commits: Option<usize>,
+    /// Finds ticket IDs in the current branch's name. Defaults to Jira-style
+    /// IDs like `PROJ-1234`; an empty pattern turns this off.
+    pub ticket_pattern: Option<String>,
+    /// Where ticket IDs from the branch name go in the message.
+    pub ticket_placement: Placement,
+    /// What to do when the changes contain something that looks like a
+    /// secret. They are masked before being sent by default.
+    pub secrets: SecretPolicy,
+    /// Regular expressions for secrets of your own, masked like the built-in
+    /// ones (AWS keys, private keys, JWTs, API tokens, random strings).
+    pub redact_patterns: Option<Vec<String>>,
+    /// Whether generated messages are checked against the subject and body
+    /// rules, with what can't be fixed locally sent back to the model.
+    /// Defaults to `true`.
+    pub lint: Option<bool>,
+    /// Appended to every message, e.g. a `Generated-by: happycommit ({model})`
+    /// trailer. Nothing is appended when unset.
+    pub footer: Option<String>,
+}
+
+impl Config {
+    pub fn path() -> PathBuf {
+        dirs::home_dir().unwrap().join(".happycommit/config.toml")
+    }
+
+    /// Load the config file, treating a missing file as an empty config.
+    pub fn load() -> Result<Config> {
+        let path = Config::path();
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .parse()
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// The OpenAI API key, from the config file or else from `.env`.
+    pub fn api_key(&self) -> Result<String> {
+        if let Some(api_key) = &self.openai_api_key {
+            return Ok(api_key.clone());
+        }
+
+        dotenv::dotenv().ok();
+        dotenv::var("OPENAI_API_KEY").map_err(|_| {
+            anyhow!(
+                "OPENAI_API_KEY must be set in .env file or ~/.happycommit/config.toml, run `happycommit setup` to store one"
+            )
+        })
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1)
+    }
+
+    pub fn protected_branches(&self) -> Vec<String> {
+        match &self.protected_branches {
+            Some(branches) => branches.clone(),
+            None => DEFAULT_PROTECTED_BRANCHES.map(String::from).to_vec(),
+        }
+    }
+
+    /// The rules the prompt asks the model to follow, and that its answers
+    /// are checked against.
+    pub fn conventions(&self) -> Conventions {
+        let conventional_types = match self.style {
+            Style::Plain => None,
+            Style::Conventional => Some(match &self.conventional_types {
+                Some(types) => types.clone(),
+                None => conventional::DEFAULT_TYPES.map(String::from).to_vec(),
+            }),
+        };
+        Conventions {
+            conventional_types,
+            ..Default::default()
+        }
+    }
+
+    pub fn history_commits(&self) -> usize {
+        self.history_commits
+            .unwrap_or(history::DEFAULT_HISTORY_COMMITS)
+    }
+
+    pub fn ticket_pattern(&self) -> Result<Regex> {
+        let pattern = self
+            .ticket_pattern
+            .as_deref()
+            .unwrap_or(ticket::DEFAULT_PATTERN);
+        Regex::new(pattern).with_context(|| format!("Invalid ticket_pattern `{}`", pattern))
+    }
+
+    pub fn redactor(&self) -> Result<Redactor> {
+        Redactor::new(self.redact_patterns.as_deref().unwrap_or_default())
+    }
+
+    pub fn lint(&self) -> bool {
+        self.lint.unwrap_or(true)
+    }
+
+    /// The footer with `{model}` filled in, if one is configured.
+    pub fn footer(&self, model: &str) -> Option<String> {
+        self.footer
+            .as_ref()
+            .map(|footer| footer.replace("{model}", model))
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_seconds.map(Duration::from_secs)
+    }
+}
+
+/// The settings in effect, one `key = value` line each, with the API key
+/// masked so the output can be pasted into a bug report.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn line(
+            f: &mut fmt::Formatter<'_>,
+            key: &str,
+            value: Option<impl fmt::Debug>,
+        ) -> fmt::Result {
+            match value {
+                Some(value) => writeln!(f, "{} = {:?}", key, value),
+                None => writeln!(f, "# {} is not set", key),
+            }
+        }
+
+        let api_key = self
+            .openai_api_key
+            .as_deref()
+            .map(|key| format!("{}...", key.chars().take(3).collect::<String>()));
+        line(f, "OPENAI_API_KEY", api_key)?;
+        line(f, "provider", Some(self.provider.to_string()))?;
+        line(f, "model", self.model.as_ref())?;
+        line(f, "api_base", self.api_base.as_ref())?;
+        line(f, "api_version", self.api_version.as_ref())?;
+        line(f, "deployment", self.deployment.as_ref())?;
+        line(f, "organization", self.organization.as_ref())?;
+        line(f, "temperature", self.temperature)?;
+        line(f, "max_tokens", self.max_tokens)?;
+        line(f, "context_window", self.context_window)?;
+        line(f, "timeout_seconds", self.timeout_seconds)?;
+        line(f, "concurrency", Some(self.concurrency()))?;
+        line(f, "response_format", Some(self.response_format.to_string()))?;
+        line(f, "create_branch", Some(self.create_branch.to_string()))?;
+        line(f, "protected_branches", Some(self.protected_branches()))?;
+        line(f, "branch_template", self.branch_template.as_ref())?;
+        line(
+            f,
+            "branch_collision",
+            Some(self.branch_collision.to_string()),
+        )?;
+        line(f, "style", Some(self.style.to_string()))?;
+        line(f, "conventional_types", self.conventional_types.as_ref())?;
+        line(f, "ticket_pattern", self.ticket_pattern.as_ref())?;
+        line(
+            f,
+            "ticket_placement",
+            Some(self.ticket_placement.to_string()),
+        )?;
+        line(f, "secrets", Some(self.secrets.to_string()))?;
+        line(f, "redact_patterns", self.redact_patterns.as_ref())?;
+        line(f, "history_commits", Some(self.history_commits()))?;
+        line(f, "lint", Some(self.lint()))?;
+        line(f, "footer", self.footer.as_ref())
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(toml::from_str(s)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = r#"
+            OPENAI_API_KEY = "sk-test"
+            provider = "ollama"
+            model = "mistral"
+            temperature = 0.2
+            max_tokens = 512
+            timeout_seconds = 30
+            create_branch = "always"
+            branch_template = "{type}/{slug}"
+            lint = false
+            ticket_placement = "trailer"
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(config.api_key().unwrap(), "sk-test");
+        assert_eq!(config.provider, ProviderKind::Ollama);
+        assert_eq!(config.model.as_deref(), Some("mistral"));
+        assert_eq!(config.temperature, Some(0.2));
+        assert_eq!(config.max_tokens, Some(512));
+        assert_eq!(config.timeout(), Some(Duration::from_secs(30)));
+        assert_eq!(config.api_base, None);
+        assert_eq!(config.create_branch, BranchMode::Always);
+        assert_eq!(config.branch_template.as_deref(), Some("{type}/{slug}"));
+        assert_eq!(config.protected_branches(), vec!["main", "master"]);
+        assert!(!config.lint());
+        assert_eq!(config.ticket_placement, Placement::Trailer);
+        assert!(config.ticket_pattern().unwrap().is_match("PROJ-1"));
+    }
+}
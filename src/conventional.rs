@@ -0,0 +1,190 @@
+//! Checking messages against the Conventional Commits spec
+//! (<https://www.conventionalcommits.org/en/v1.0.0/>).
+
+use anyhow::{anyhow, Result};
+
+use crate::commit_message::CommitMessage;
+
+/// Types allowed when `conventional_types` isn't set: the ones from the
+/// Angular convention that Conventional Commits grew out of.
+pub const DEFAULT_TYPES: [&str; 11] = [
+    "feat", "fix", "docs", "style", "refactor", "perf", "test", "build", "ci", "chore", "revert",
+];
+
+/// The `type(scope)!: description` first line of a conventional commit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub kind: String,
+    pub scope: Option<String>,
+    pub breaking: bool,
+    pub description: String,
+}
+
+impl Header {
+    pub fn parse(subject: &str) -> Result<Header> {
+        let (prefix, description) = subject
+            .split_once(": ")
+            .ok_or_else(|| anyhow!("the subject must look like `type(scope)!: description`"))?;
+
+        let (prefix, breaking) = match prefix.strip_suffix('!') {
+            Some(prefix) => (prefix, true),
+            None => (prefix, false),
+        };
+        let (kind, scope) = match prefix.split_once('(') {
+            Some((kind, scope)) => {
+                let scope = scope
+                    .strip_suffix(')')
+                    .filter(|scope| !scope.trim().is_empty() && !scope.contains(['(', ')']))
+                    .ok_or_else(|| anyhow!("the scope must be a non-empty word in parentheses"))?;
+                (kind, Some(scope.to_string()))
+            }
+            None => (prefix, None),
+        };
+
+        if kind.is_empty() || !kind.chars().all(|c| c.is_ascii_lowercase()) {
+            return Err(anyhow!(
+                "the type must be a lowercase word such as `feat` or `fix`, not `{}`",
+                kind
+            ));
+        }
+        if description.trim().is_empty() {
+            return Err(anyhow!("the description after `: ` must not be empty"));
+        }
+
+        Ok(Header {
+            kind: kind.to_string(),
+            scope,
+            breaking,
+            description: description.trim().to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(scope) = &self.scope {
+            write!(f, "({})", scope)?;
+        }
+        if self.breaking {
+            write!(f, "!")?;
+        }
+        write!(f, ": {}", self.description)
+    }
+}
+
+/// The description of a `BREAKING CHANGE:` footer in `body`, if it has one.
+fn breaking_change_footer(body: &str) -> Result<Option<&str>> {
+    for line in body.lines() {
+        let Some((token, description)) = line.split_once(':') else {
+            continue;
+        };
+        match token {
+            "BREAKING CHANGE" | "BREAKING-CHANGE" if description.trim().is_empty() => {
+                return Err(anyhow!("the `{}:` footer must describe what breaks", token));
+            }
+            "BREAKING CHANGE" | "BREAKING-CHANGE" => return Ok(Some(description.trim())),
+            token if token.eq_ignore_ascii_case("breaking change") => {
+                return Err(anyhow!("the `BREAKING CHANGE:` footer must be uppercase"));
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Check that `message` is a conventional commit with one of `allowed_types`,
+/// and fill in its type, scope and breaking flag from the header.
+///
+/// In JSON mode those come as separate fields, so a subject without a header
+/// gets one built from them rather than being rejected.
+pub fn check(mut message: CommitMessage, allowed_types: &[String]) -> Result<CommitMessage> {
+    if let (Some(kind), Err(_)) = (&message.kind, Header::parse(&message.subject)) {
+        message.subject = Header {
+            kind: kind.clone(),
+            scope: message.scope.clone(),
+            breaking: message.breaking,
+            description: message.subject.trim().to_string(),
+        }
+        .to_string();
+    }
+
+    let header = Header::parse(&message.subject)?;
+    if !allowed_types.contains(&header.kind) {
+        return Err(anyhow!(
+            "`{}` is not an allowed type, use one of {}",
+            header.kind,
+            allowed_types.join(", ")
+        ));
+    }
+    let footer = breaking_change_footer(&message.body)?;
+
+    message.breaking = header.breaking || footer.is_some();
+    message.kind = Some(header.kind);
+    message.scope = header.scope;
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn types() -> Vec<String> {
+        DEFAULT_TYPES.map(String::from).to_vec()
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let header = Header::parse("feat(parser)!: drop support for tabs").unwrap();
+        assert_eq!(header.kind, "feat");
+        assert_eq!(header.scope.as_deref(), Some("parser"));
+        assert!(header.breaking);
+        assert_eq!(header.description, "drop support for tabs");
+        assert_eq!(header.to_string(), "feat(parser)!: drop support for tabs");
+
+        assert!(Header::parse("Add a parser").is_err());
+        assert!(Header::parse("Feat: add a parser").is_err());
+        assert!(Header::parse("feat(): add a parser").is_err());
+        assert!(Header::parse("feat: ").is_err());
+    }
+
+    #[test]
+    fn test_check_types_and_footers() {
+        let breaking = CommitMessage {
+            subject: "fix: handle empty diffs".to_string(),
+            body: "BREAKING CHANGE: exits with 1".to_string(),
+            ..Default::default()
+        };
+        let checked = check(breaking, &types()).unwrap();
+        assert_eq!(checked.kind.as_deref(), Some("fix"));
+        assert!(checked.breaking);
+
+        let unknown_type = CommitMessage {
+            subject: "wip: handle empty diffs".to_string(),
+            ..Default::default()
+        };
+        assert!(check(unknown_type, &types()).is_err());
+
+        let lowercase_footer = CommitMessage {
+            subject: "fix: handle empty diffs".to_string(),
+            body: "Breaking change: yes".to_string(),
+            ..Default::default()
+        };
+        assert!(check(lowercase_footer, &types()).is_err());
+    }
+
+    #[test]
+    fn test_check_builds_header_from_json_fields() {
+        let json = CommitMessage {
+            kind: Some("feat".to_string()),
+            scope: Some("cli".to_string()),
+            breaking: true,
+            subject: "add a --json flag".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            check(json, &types()).unwrap().subject,
+            "feat(cli)!: add a --json flag"
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! Splitting a unified diff into slices that fit the token budget.
+//!
+//! Slices are cut on file and hunk boundaries, and each slice repeats the
+//! `diff --git` header of every file it touches, plus the `@@` header of a
+//! hunk that had to be cut up. Splitting on raw tokens only happens for a
+//! single hunk that is too big to fit in a slice on its own.
+
+use anyhow::Result;
+use tiktoken_rs::CoreBPE;
+
+/// The changes to one file: everything from `diff --git` up to the first
+/// hunk, followed by the hunks themselves.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileDiff {
+    pub header: String,
+    pub hunks: Vec<Hunk>,
+}
+
+impl FileDiff {
+    /// The path of the file after the change, from the `diff --git` line.
+    pub fn path(&self) -> Option<&str> {
+        header_path(self.header.lines().next()?)
+    }
+
+    /// How many lines were added or removed.
+    pub fn changed_lines(&self) -> usize {
+        self.hunks
+            .iter()
+            .flat_map(|hunk| hunk.body.lines())
+            .filter(|line| line.starts_with(['+', '-']))
+            .count()
+    }
+}
+
+/// The path after the change in a `diff --git a/<old> b/<new>` line.
+pub fn header_path(line: &str) -> Option<&str> {
+    let paths = line.strip_prefix("diff --git ")?.trim_end();
+    Some(paths.rsplit_once(" b/").map_or(paths, |(_, path)| path))
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hunk {
+    /// The `@@ -a,b +c,d @@` line.
+    pub header: String,
+    pub body: String,
+}
+
+impl Hunk {
+    fn text(&self) -> String {
+        format!("{}{}", self.header, self.body)
+    }
+}
+
+/// Parse the output of `git diff` into per-file sections.
+pub fn parse(diff: &str) -> Vec<FileDiff> {
+    let mut files: Vec<FileDiff> = Vec::new();
+
+    for line in diff.split_inclusive('\n') {
+        if files.is_empty() || line.starts_with("diff --git ") {
+            files.push(FileDiff::default());
+        }
+        let file = files.last_mut().unwrap();
+
+        if line.starts_with("@@") {
+            file.hunks.push(Hunk {
+                header: line.to_string(),
+                body: String::new(),
+            });
+        } else if let Some(hunk) = file.hunks.last_mut() {
+            hunk.body.push_str(line);
+        } else {
+            file.header.push_str(line);
+        }
+    }
+
+    files
+}
+
+/// Split `diff` into slices of at most `max_tokens` tokens each.
+///
+/// `overlap` only applies when a single hunk has to be split by tokens.
+pub fn split(
+    diff: &str,
+    tokenizer: &CoreBPE,
+    max_tokens: usize,
+    overlap: usize,
+) -> Result<Vec<String>> {
+    let count = |text: &str| tokenizer.encode_with_special_tokens(text).len();
+
+    let mut slices = Vec::new();
+    let mut current = String::new();
+    let mut current_tokens = 0;
+
+    for file in parse(diff) {
+        let header_tokens = count(&file.header);
+        // whether `current` already starts this file's section
+        let mut header_in_current = false;
+
+        if file.hunks.is_empty() {
+            // binary files, renames and mode changes have nothing but a header
+            if current_tokens + header_tokens > max_tokens && !current.is_empty() {
+                slices.push(std::mem::take(&mut current));
+                current_tokens = 0;
+            }
+            if header_tokens > max_tokens {
+                slices.extend(split_by_tokens(
+                    &file.header,
+                    tokenizer,
+                    max_tokens,
+                    overlap,
+                )?);
+            } else {
+                current.push_str(&file.header);
+                current_tokens += header_tokens;
+            }
+            continue;
+        }
+
+        for hunk in &file.hunks {
+            let hunk_text = hunk.text();
+            let hunk_tokens = count(&hunk_text);
+            let needed = hunk_tokens + if header_in_current { 0 } else { header_tokens };
+
+            if current_tokens + needed <= max_tokens {
+                if !header_in_current {
+                    current.push_str(&file.header);
+                    header_in_current = true;
+                }
+                current.push_str(&hunk_text);
+                current_tokens += needed;
+                continue;
+            }
+
+            if !current.is_empty() {
+                slices.push(std::mem::take(&mut current));
+                current_tokens = 0;
+            }
+
+            if header_tokens + hunk_tokens <= max_tokens {
+                current.push_str(&file.header);
+                current.push_str(&hunk_text);
+                current_tokens = header_tokens + hunk_tokens;
+                header_in_current = true;
+                continue;
+            }
+
+            // The hunk doesn't fit even on its own, so cut its body by tokens
+            // and give every piece the file and hunk headers for context.
+            let context = format!("{}{}", file.header, hunk.header);
+            let piece_tokens = max_tokens.saturating_sub(count(&context)).max(1);
+            let piece_overlap = overlap.min(piece_tokens / 2);
+            for piece in split_by_tokens(&hunk.body, tokenizer, piece_tokens, piece_overlap)? {
+                slices.push(format!("{}{}", context, piece));
+            }
+            header_in_current = false;
+        }
+    }
+
+    if !current.is_empty() {
+        slices.push(current);
+    }
+
+    Ok(slices)
+}
+
+/// Cut `text` into pieces of `max_tokens` tokens, each sharing `overlap`
+/// tokens with the one before it.
+fn split_by_tokens(
+    text: &str,
+    tokenizer: &CoreBPE,
+    max_tokens: usize,
+    overlap: usize,
+) -> Result<Vec<String>> {
+    let tokens = tokenizer.split_by_token(text, true)?;
+    let mut pieces = Vec::new();
+    let mut start = 0;
+
+    while start < tokens.len() {
+        let end = (start + max_tokens).min(tokens.len());
+        pieces.push(tokens[start..end].concat());
+        if end == tokens.len() {
+            break;
+        }
+        start = end - overlap.min(end - start - 1);
+    }
+
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiktoken_rs::cl100k_base;
+
+    const DIFF: &str = "diff --git a/src/a.rs b/src/a.rs
+index 1111111..2222222 100644
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -1,3 +1,3 @@
+ fn a() {
+-    1
++    2
+ }
+@@ -10,3 +10,3 @@
+ fn b() {
+-    3
++    4
+ }
+diff --git a/src/b.rs b/src/b.rs
+index 3333333..4444444 100644
+--- a/src/b.rs
++++ b/src/b.rs
+@@ -1 +1 @@
+-old
++new
+";
+
+    #[test]
+    fn test_parse_files_and_hunks() {
+        let files = parse(DIFF);
+        assert_eq!(files.len(), 2);
+        assert!(files[0].header.starts_with("diff --git a/src/a.rs"));
+        assert!(files[0].header.ends_with("+++ b/src/a.rs\n"));
+        assert_eq!(files[0].hunks.len(), 2);
+        assert_eq!(files[0].hunks[1].header, "@@ -10,3 +10,3 @@\n");
+        assert_eq!(files[1].hunks[0].body, "-old\n+new\n");
+        assert_eq!(files[0].path(), Some("src/a.rs"));
+        assert_eq!(files[0].changed_lines(), 4);
+    }
+
+    #[test]
+    fn test_small_diff_is_one_slice() {
+        let tokenizer = cl100k_base().unwrap();
+        let slices = split(DIFF, &tokenizer, 1000, 10).unwrap();
+        assert_eq!(slices, vec![DIFF.to_string()]);
+    }
+
+    #[test]
+    fn test_slices_repeat_file_headers() {
+        let tokenizer = cl100k_base().unwrap();
+        let hunk_tokens = tokenizer
+            .encode_with_special_tokens(&parse(DIFF)[0].hunks[0].text())
+            .len();
+        let header_tokens = tokenizer
+            .encode_with_special_tokens(&parse(DIFF)[0].header)
+            .len();
+
+        // room for the header and one hunk, but not two
+        let slices = split(DIFF, &tokenizer, header_tokens + hunk_tokens + 2, 0).unwrap();
+        assert_eq!(slices.len(), 3);
+        assert!(slices[0].starts_with("diff --git a/src/a.rs"));
+        assert!(slices[1].starts_with("diff --git a/src/a.rs"));
+        assert!(slices[1].contains("@@ -10,3 +10,3 @@"));
+        assert!(slices[2].starts_with("diff --git a/src/b.rs"));
+    }
+
+    #[test]
+    fn test_oversized_hunk_falls_back_to_tokens() {
+        let tokenizer = cl100k_base().unwrap();
+        let body = "+let x = 1;\n".repeat(200);
+        let diff = format!(
+            "diff --git a/big.rs b/big.rs\n--- a/big.rs\n+++ b/big.rs\n@@ -0,0 +1,200 @@\n{}",
+            body
+        );
+
+        let slices = split(&diff, &tokenizer, 300, 20).unwrap();
+        assert!(slices.len() > 1);
+        for slice in &slices {
+            assert!(slice.starts_with("diff --git a/big.rs b/big.rs\n"));
+            assert!(slice.contains("@@ -0,0 +1,200 @@\n"));
+            assert!(tokenizer.encode_with_special_tokens(slice).len() <= 300);
+        }
+    }
+}
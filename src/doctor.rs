@@ -0,0 +1,209 @@
+//! `happycommit doctor`: check that everything a commit needs is in place,
+//! without spending any tokens.
+
+use std::process::Command;
+
+use crate::{
+    changes::Source,
+    config::Config,
+    provider,
+    provider::ProviderKind,
+    repo::{Repo, RepoError},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Check {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+
+    fn print(&self) {
+        let label = match self.status {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        };
+        println!("[{}] {}: {}", label, self.name, self.detail);
+    }
+}
+
+/// Run every check, printing each result as it comes in. Returns whether
+/// none of them failed.
+///
+/// `model` overrides the model from the config file, as `--model` does.
+pub async fn run(model: Option<&str>) -> bool {
+    let mut checks = Vec::new();
+    let mut record = |check: Check| {
+        check.print();
+        checks.push(check.status);
+    };
+
+    let config = match Config::load() {
+        Ok(mut config) => {
+            if let Some(model) = model {
+                config.model = Some(model.to_string());
+            }
+            record(Check::new(
+                "config",
+                Status::Pass,
+                format!("loaded {}", Config::path().display()),
+            ));
+            Some(config)
+        }
+        Err(e) => {
+            record(Check::new("config", Status::Fail, format!("{:#}", e)));
+            None
+        }
+    };
+
+    if let Some(config) = &config {
+        record(check_api_key(config));
+
+        match provider::from_config(config) {
+            Ok(provider) => match provider.list_models().await {
+                Ok(models) => {
+                    record(Check::new(
+                        "endpoint",
+                        Status::Pass,
+                        format!("{} is reachable", config.provider),
+                    ));
+                    let model = provider.model();
+                    let available = models
+                        .iter()
+                        .any(|name| name == model || name.starts_with(&format!("{}:", model)));
+                    record(if available {
+                        Check::new("model", Status::Pass, format!("{} is available", model))
+                    } else if config.provider == ProviderKind::LlamaCpp {
+                        // llama.cpp serves whichever model it was started with, whatever it's called
+                        Check::new(
+                            "model",
+                            Status::Warn,
+                            format!(
+                                "{} is not listed, the server will use its loaded model",
+                                model
+                            ),
+                        )
+                    } else if let Some(deployment) = azure_deployment(config) {
+                        // Azure lists the models it offers, not the ones deployed on the resource
+                        Check::new(
+                            "model",
+                            Status::Warn,
+                            format!(
+                                "{} is not listed, requests go to the {} deployment",
+                                model, deployment
+                            ),
+                        )
+                    } else {
+                        Check::new("model", Status::Fail, format!("{} is not available", model))
+                    });
+                }
+                Err(e) => record(Check::new("endpoint", Status::Fail, format!("{:#}", e))),
+            },
+            Err(e) => record(Check::new("endpoint", Status::Fail, format!("{:#}", e))),
+        }
+    }
+
+    record(check_git_version());
+    for check in check_repository() {
+        record(check);
+    }
+
+    !checks.contains(&Status::Fail)
+}
+
+fn azure_deployment(config: &Config) -> Option<&str> {
+    config.api_version.as_ref()?;
+    config.deployment.as_deref().or(config.model.as_deref())
+}
+
+fn check_api_key(config: &Config) -> Check {
+    if config.provider != ProviderKind::OpenAi {
+        return Check::new(
+            "api key",
+            Status::Pass,
+            format!("not needed for {}", config.provider),
+        );
+    }
+    if config.openai_api_key.is_some() {
+        return Check::new("api key", Status::Pass, "set in config file");
+    }
+    match config.api_key() {
+        Ok(_) => Check::new("api key", Status::Pass, "set in .env or the environment"),
+        Err(e) => Check::new("api key", Status::Fail, e.to_string()),
+    }
+}
+
+fn git(args: &[&str]) -> Option<std::process::Output> {
+    Command::new("git").args(args).output().ok()
+}
+
+fn check_git_version() -> Check {
+    match git(&["--version"]) {
+        Some(output) if output.status.success() => Check::new(
+            "git",
+            Status::Pass,
+            String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        ),
+        _ => Check::new("git", Status::Fail, "git is not installed or not on PATH"),
+    }
+}
+
+fn check_repository() -> Vec<Check> {
+    let repo = match Repo::discover() {
+        Ok(repo) => repo,
+        Err(RepoError::NotARepository) => {
+            return vec![Check::new(
+                "repository",
+                Status::Fail,
+                "the current directory is not inside a git repository",
+            )]
+        }
+        Err(e) => {
+            return vec![Check::new(
+                "repository",
+                Status::Fail,
+                format!("could not open the repository: {}", e),
+            )]
+        }
+    };
+
+    let mut checks = Vec::new();
+
+    checks.push(match repo.current_branch() {
+        Ok(branch) => Check::new("repository", Status::Pass, format!("on branch {}", branch)),
+        Err(RepoError::DetachedHead) => Check::new("repository", Status::Warn, "HEAD is detached"),
+        Err(e) => Check::new("repository", Status::Fail, e.to_string()),
+    });
+
+    checks.push(match repo.read(&Source::Staged) {
+        Ok(_) => Check::new("staged changes", Status::Pass, "ready to commit"),
+        Err(RepoError::NothingStaged) => Check::new(
+            "staged changes",
+            Status::Warn,
+            "nothing is staged yet, use git add first",
+        ),
+        Err(e) => Check::new(
+            "staged changes",
+            Status::Fail,
+            format!("could not read the index: {}", e),
+        ),
+    });
+
+    checks
+}
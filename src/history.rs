@@ -0,0 +1,203 @@
+//! Learning a repository's commit style from its recent history.
+//!
+//! The general instructions in the prompt describe good commit messages in
+//! the abstract. Projects have their own habits on top of that, such as
+//! ticket prefixes or gitmoji, which are easiest to pick up from a few of
+//! their own messages.
+
+use crate::conventional::Header;
+
+/// Commits sampled when `history_commits` isn't set.
+pub const DEFAULT_HISTORY_COMMITS: usize = 20;
+
+/// How many sampled messages are shown to the model as examples.
+const EXAMPLES: usize = 3;
+
+/// Messages longer than this make poor examples and eat into the diff's budget.
+const MAX_EXAMPLE_LINES: usize = 15;
+
+/// Habits shared by most of the sampled messages, described for the prompt,
+/// plus a few of the messages themselves as examples.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepoStyle {
+    pub observations: Vec<String>,
+    pub examples: Vec<String>,
+}
+
+/// Whether more than half of `total` have some property.
+fn most(count: usize, total: usize) -> bool {
+    count * 2 > total
+}
+
+/// A ticket ID like `PROJ-123` at the start of `subject`, possibly in brackets.
+fn ticket_prefix(subject: &str) -> Option<&str> {
+    let word = subject.split_whitespace().next()?;
+    let word = word.trim_matches(|c| matches!(c, '[' | ']' | '(' | ')' | ':'));
+    let (project, number) = word.split_once('-')?;
+    let is_ticket = !project.is_empty()
+        && project
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+        && project.starts_with(|c: char| c.is_ascii_uppercase())
+        && !number.is_empty()
+        && number.chars().all(|c| c.is_ascii_digit());
+    is_ticket.then_some(word)
+}
+
+/// Whether `subject` starts with an emoji or a `:shortcode:`.
+fn starts_with_gitmoji(subject: &str) -> bool {
+    if let Some(rest) = subject.strip_prefix(':') {
+        if let Some((code, _)) = rest.split_once(':') {
+            return !code.is_empty() && code.chars().all(|c| c.is_ascii_lowercase() || c == '_');
+        }
+    }
+    subject
+        .chars()
+        .next()
+        .is_some_and(|c| !c.is_ascii() && !c.is_alphanumeric())
+}
+
+/// The subject without any ticket ID, gitmoji or conventional prefix.
+pub fn description(subject: &str) -> &str {
+    let mut rest = subject;
+    if let Some(ticket) = ticket_prefix(rest) {
+        rest = rest[rest.find(ticket).unwrap_or(0) + ticket.len()..]
+            .trim_start_matches(|c: char| matches!(c, ']' | ')' | ':') || c.is_whitespace());
+    }
+    if starts_with_gitmoji(rest) {
+        rest = rest
+            .split_once(char::is_whitespace)
+            .map_or("", |(_, rest)| rest.trim_start());
+    }
+    if Header::parse(rest).is_ok() {
+        rest = rest.split_once(": ").map_or(rest, |(_, rest)| rest);
+    }
+    rest
+}
+
+/// Work out the habits the sampled `messages` have in common.
+pub fn infer(messages: &[String]) -> RepoStyle {
+    let total = messages.len();
+    if total == 0 {
+        return RepoStyle::default();
+    }
+
+    let subjects: Vec<&str> = messages
+        .iter()
+        .map(|message| message.lines().next().unwrap_or_default().trim())
+        .collect();
+    let bodies: Vec<usize> = messages
+        .iter()
+        .map(|message| {
+            message
+                .lines()
+                .skip(1)
+                .filter(|line| !line.trim().is_empty())
+                .count()
+        })
+        .collect();
+    let count = |matches: &dyn Fn(&str) -> bool| subjects.iter().filter(|s| matches(s)).count();
+
+    let mut observations = Vec::new();
+
+    if most(count(&|s| Header::parse(s).is_ok()), total) {
+        observations.push(
+            "Subjects are Conventional Commits headers, such as `fix(parser): handle empty input`."
+                .to_string(),
+        );
+    }
+    let tickets: Vec<&str> = subjects.iter().filter_map(|s| ticket_prefix(s)).collect();
+    if most(tickets.len(), total) {
+        observations.push(format!(
+            "Subjects start with a ticket ID, such as `{}`.",
+            tickets[0]
+        ));
+    }
+    if most(count(&|s| starts_with_gitmoji(s)), total) {
+        observations.push("Subjects start with a gitmoji.".to_string());
+    }
+
+    let capitalized = count(&|s| description(s).starts_with(|c: char| c.is_uppercase()));
+    let lowercase = count(&|s| description(s).starts_with(|c: char| c.is_lowercase()));
+    if most(capitalized, total) {
+        observations.push("Subjects start with a capital letter.".to_string());
+    } else if most(lowercase, total) {
+        observations.push("Subjects start with a lowercase letter.".to_string());
+    }
+    if most(count(&|s| s.ends_with('.')), total) {
+        observations.push("Subjects end with a period.".to_string());
+    }
+
+    let with_body: Vec<usize> = bodies.iter().copied().filter(|lines| *lines > 0).collect();
+    if !most(with_body.len(), total) {
+        observations.push("Most commits have a subject line only, without a body.".to_string());
+    } else {
+        let average = with_body.iter().sum::<usize>() / with_body.len();
+        observations.push(format!(
+            "Bodies are usually around {} line(s) long.",
+            average.max(1)
+        ));
+    }
+
+    let examples = messages
+        .iter()
+        .filter(|message| message.lines().count() <= MAX_EXAMPLE_LINES)
+        .take(EXAMPLES)
+        .cloned()
+        .collect();
+
+    RepoStyle {
+        observations,
+        examples,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(messages: &[&str]) -> Vec<String> {
+        messages.iter().map(|m| m.to_string()).collect()
+    }
+
+    #[test]
+    fn test_infer_ticket_prefixes_and_lowercase() {
+        let style = infer(&messages(&[
+            "PROJ-12: add retries to the uploader",
+            "[PROJ-9] fix the flaky login test\n\nIt raced the session cookie.",
+            "PROJ-7 bump dependencies",
+        ]));
+        assert!(style
+            .observations
+            .contains(&"Subjects start with a ticket ID, such as `PROJ-12`.".to_string()));
+        assert!(style
+            .observations
+            .contains(&"Subjects start with a lowercase letter.".to_string()));
+        assert!(style
+            .observations
+            .contains(&"Most commits have a subject line only, without a body.".to_string()));
+        assert_eq!(style.examples.len(), 3);
+    }
+
+    #[test]
+    fn test_infer_conventional_gitmoji_and_bodies() {
+        let style = infer(&messages(&[
+            "✨ feat: Add dark mode\n\nFollows the system setting.\nCan be overridden.",
+            ":bug: fix(ui): Fix overflow\n\nLong names no longer wrap.",
+        ]));
+        assert!(style
+            .observations
+            .contains(&"Subjects start with a gitmoji.".to_string()));
+        assert!(style
+            .observations
+            .contains(&"Subjects start with a capital letter.".to_string()));
+        assert!(style
+            .observations
+            .contains(&"Bodies are usually around 1 line(s) long.".to_string()));
+    }
+
+    #[test]
+    fn test_no_history() {
+        assert_eq!(infer(&[]), RepoStyle::default());
+    }
+}
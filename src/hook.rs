@@ -0,0 +1,158 @@
+//! Running as git's `prepare-commit-msg` hook, and installing that hook.
+//!
+//! The hook itself is a two-line shell script that calls back into
+//! `happycommit hook prepare-commit-msg`, which writes the generated message
+//! into the file git is about to open in the editor.
+
+use std::{fs, path::Path, path::PathBuf, process::Command};
+
+use anyhow::{anyhow, Context, Result};
+
+pub const HOOK_NAME: &str = "prepare-commit-msg";
+
+/// Identifies hooks written by `install`, so nothing else gets overwritten or removed.
+const MARKER: &str = "# Installed by happycommit";
+
+// A failed generation shouldn't stop anyone from committing, so the hook
+// always succeeds and git falls back to the usual empty message.
+const SCRIPT: &str = "#!/bin/sh
+# Installed by happycommit. Remove with `happycommit hook uninstall`.
+happycommit hook prepare-commit-msg \"$@\" || true
+";
+
+/// Where git looks for hooks in the current repository, honouring `core.hooksPath`.
+fn hooks_dir() -> Result<PathBuf> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--git-path", "hooks"])
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Not in a git repository: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(PathBuf::from(
+        String::from_utf8_lossy(&output.stdout).trim(),
+    ))
+}
+
+fn is_ours(path: &Path) -> bool {
+    fs::read_to_string(path).is_ok_and(|contents| contents.contains(MARKER))
+}
+
+/// Write the hook into the current repository, returning its path. An existing
+/// hook that happycommit didn't write is only replaced with `force`.
+pub fn install(force: bool) -> Result<PathBuf> {
+    let dir = hooks_dir()?;
+    let path = dir.join(HOOK_NAME);
+    if path.exists() && !force && !is_ours(&path) {
+        return Err(anyhow!(
+            "{} already exists, use --force to replace it",
+            path.display()
+        ));
+    }
+
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    fs::write(&path, SCRIPT).with_context(|| format!("Failed to write {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    }
+
+    Ok(path)
+}
+
+/// Remove the hook from the current repository, returning its path.
+pub fn uninstall() -> Result<PathBuf> {
+    let path = hooks_dir()?.join(HOOK_NAME);
+    if !path.exists() {
+        return Err(anyhow!("{} is not installed", path.display()));
+    }
+    if !is_ours(&path) {
+        return Err(anyhow!(
+            "{} wasn't installed by happycommit, leaving it alone",
+            path.display()
+        ));
+    }
+
+    fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    Ok(path)
+}
+
+/// Whether a commit with this message source should get a generated message.
+///
+/// git passes no source for a plain `git commit`, and `template` when
+/// `commit.template` is set. Messages given with `-m`/`-F` (`message`), merges,
+/// squashes and amends (`commit`) already have a message worth keeping.
+pub fn should_fill(source: Option<&str>) -> bool {
+    matches!(source, None | Some("template"))
+}
+
+/// The line `git commit -v` puts above the diff. git ignores everything
+/// below it, so it and the diff are kept as they are.
+const SCISSORS: &str = "# ------------------------ >8 ------------------------";
+
+/// Write `message` into the commit message file in place of whatever text
+/// git put there, such as a `commit.template`, keeping only its comments.
+pub fn fill(path: &Path, message: &str) -> Result<()> {
+    let existing =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let (above, below) = existing.split_at(existing.find(SCISSORS).unwrap_or(existing.len()));
+    let comments: String = above
+        .lines()
+        .filter(|line| line.starts_with('#'))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    fs::write(
+        path,
+        format!("{}\n\n{}{}", message.trim_end(), comments, below),
+    )
+    .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_fill() {
+        assert!(should_fill(None));
+        assert!(should_fill(Some("template")));
+        for source in ["message", "merge", "squash", "commit"] {
+            assert!(!should_fill(Some(source)));
+        }
+    }
+
+    #[test]
+    fn test_fill_keeps_status_comments() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), "\n# Please enter the commit message\n").unwrap();
+
+        fill(
+            file.path(),
+            "Add retries\n\nThe uploader gave up too early.\n",
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(file.path()).unwrap(),
+            "Add retries\n\nThe uploader gave up too early.\n\n# Please enter the commit message\n"
+        );
+    }
+
+    #[test]
+    fn test_fill_replaces_template_text() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(
+            file.path(),
+            "Summary:\n\nTicket:\n# Please enter the commit message\n#\n# ------------------------ >8 ------------------------\ndiff --git a/x b/x\n",
+        )
+        .unwrap();
+
+        fill(file.path(), "Add retries\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(file.path()).unwrap(),
+            "Add retries\n\n# Please enter the commit message\n#\n# ------------------------ >8 ------------------------\ndiff --git a/x b/x\n"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! `.happycommitignore`: files whose changes are summed up instead of sent.
+//!
+//! Lockfiles, generated code and snapshots can make up most of a diff while
+//! saying little about it. Files matching the ignore file (in gitignore
+//! syntax, at the top of the work tree) or one of the [`DEFAULT_PATTERNS`]
+//! keep their `diff --git` line, but their hunks are replaced with a single
+//! "N lines changed in <path>" line.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::diff;
+
+pub const FILE_NAME: &str = ".happycommitignore";
+
+/// Common lockfiles. A `!Cargo.lock` line in the ignore file brings one back.
+pub const DEFAULT_PATTERNS: [&str; 13] = [
+    "Cargo.lock",
+    "package-lock.json",
+    "npm-shrinkwrap.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "bun.lockb",
+    "poetry.lock",
+    "Pipfile.lock",
+    "composer.lock",
+    "Gemfile.lock",
+    "go.sum",
+    "flake.lock",
+    "mix.lock",
+];
+
+/// The default patterns followed by those in `root`'s ignore file, if it has one.
+pub fn load(root: &Path) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in DEFAULT_PATTERNS {
+        builder.add_line(None, pattern)?;
+    }
+    let path = root.join(FILE_NAME);
+    if path.exists() {
+        if let Some(e) = builder.add(&path) {
+            return Err(anyhow!("Failed to read {}: {}", path.display(), e));
+        }
+    }
+    Ok(builder.build()?)
+}
+
+/// Replace the hunks of ignored files in `diff` with a one-line summary,
+/// returning the new diff and the paths that were summed up.
+pub fn apply(diff: &str, rules: &Gitignore) -> (String, Vec<String>) {
+    let mut kept = String::with_capacity(diff.len());
+    let mut ignored = Vec::new();
+
+    for file in diff::parse(diff) {
+        let path = file.path().map(String::from);
+        let matched = path
+            .as_deref()
+            .is_some_and(|path| rules.matched_path_or_any_parents(path, false).is_ignore());
+        match path {
+            Some(path) if matched && !file.hunks.is_empty() => {
+                let first_line = file.header.lines().next().unwrap_or_default();
+                kept.push_str(&format!(
+                    "{}\n{} lines changed in {}\n",
+                    first_line,
+                    file.changed_lines(),
+                    path
+                ));
+                ignored.push(path);
+            }
+            _ => {
+                kept.push_str(&file.header);
+                for hunk in &file.hunks {
+                    kept.push_str(&hunk.header);
+                    kept.push_str(&hunk.body);
+                }
+            }
+        }
+    }
+
+    (kept, ignored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFF: &str = "diff --git a/Cargo.lock b/Cargo.lock
+index 1111111..2222222 100644
+--- a/Cargo.lock
++++ b/Cargo.lock
+@@ -1,3 +1,4 @@
+ [[package]]
+-name = \"a\"
++name = \"b\"
++version = \"1.0.0\"
+diff --git a/src/gen/api.rs b/src/gen/api.rs
+index 3333333..4444444 100644
+--- a/src/gen/api.rs
++++ b/src/gen/api.rs
+@@ -1 +1 @@
+-old
++new
+diff --git a/src/main.rs b/src/main.rs
+index 5555555..6666666 100644
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1 +1 @@
+-fn main() {}
++fn main() { run() }
+";
+
+    #[test]
+    fn test_ignored_files_are_summed_up() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join(FILE_NAME), "# generated\nsrc/gen/\n").unwrap();
+        let rules = load(root.path()).unwrap();
+
+        let (kept, ignored) = apply(DIFF, &rules);
+        assert_eq!(ignored, vec!["Cargo.lock", "src/gen/api.rs"]);
+        assert!(kept.starts_with(
+            "diff --git a/Cargo.lock b/Cargo.lock\n3 lines changed in Cargo.lock\n\
+             diff --git a/src/gen/api.rs b/src/gen/api.rs\n2 lines changed in src/gen/api.rs\n"
+        ));
+        assert!(kept.ends_with("-fn main() {}\n+fn main() { run() }\n"));
+    }
+
+    #[test]
+    fn test_defaults_can_be_negated() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join(FILE_NAME), "!Cargo.lock\n").unwrap();
+        let (kept, ignored) = apply(DIFF, &load(root.path()).unwrap());
+        assert!(ignored.is_empty());
+        assert_eq!(kept, DIFF);
+    }
+}
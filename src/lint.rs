@@ -0,0 +1,454 @@
+//! Checking commit messages against the rules the prompt asks the model for.
+//!
+//! Some violations can be fixed locally, such as rewrapping a long body or
+//! dropping a trailing period; the rest are sent back to the model. The same
+//! checks run on hand-written messages through `happycommit lint`, which is
+//! meant to be called from a `commit-msg` hook.
+
+use std::{fmt, fs, path::Path};
+
+use anyhow::{Context, Result};
+
+use crate::{commit_message::CommitMessage, history};
+
+/// Longest subject line, in characters.
+pub const SUBJECT_LIMIT: usize = 50;
+
+/// Column the body is wrapped at.
+pub const BODY_WIDTH: usize = 72;
+
+/// Lines from here on are cut off by `git commit --verbose`.
+const SCISSORS: &str = "# ------------------------ >8 ------------------------";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rule {
+    SubjectLength,
+    Imperative,
+    TrailingPeriod,
+    BlankLine,
+    BodyWidth,
+    CodeBlock,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rule::SubjectLength => "subject-length",
+            Rule::Imperative => "imperative",
+            Rule::TrailingPeriod => "trailing-period",
+            Rule::BlankLine => "blank-line",
+            Rule::BodyWidth => "body-width",
+            Rule::CodeBlock => "code-block",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub rule: Rule,
+    /// 1-based line of the message the violation is on.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {} ({})", self.line, self.message, self.rule)
+    }
+}
+
+/// The message without git's comment lines and anything below the scissors
+/// line, the way git will commit it.
+pub fn strip_comments(text: &str) -> String {
+    let mut message = String::new();
+    for line in text.lines() {
+        if line == SCISSORS {
+            break;
+        }
+        if !line.starts_with('#') {
+            message.push_str(line);
+            message.push('\n');
+        }
+    }
+    message.trim().to_string() + "\n"
+}
+
+/// Words in the past tense, the gerund or the third person, like `Added`,
+/// `Adding` or `Adds`, rather than the imperative `Add`. A heuristic: it
+/// knows about common exceptions like `Embed` or `Bring`, but not all.
+fn is_imperative(word: &str) -> bool {
+    let word = word.to_lowercase();
+    let past = word.len() > 4
+        && word.ends_with("ed")
+        && !word.ends_with("eed")
+        && !["embed", "shred"].contains(&word.as_str());
+    let gerund = word.len() > 5
+        && word.ends_with("ing")
+        && !["bring", "string", "spring", "swing", "thing"].contains(&word.as_str());
+    let third_person = word.len() > 3
+        && word.ends_with('s')
+        && !["ss", "us", "is", "as", "os"]
+            .iter()
+            .any(|ending| word.ends_with(ending));
+    !(past || gerund || third_person)
+}
+
+/// A `Token: value` trailer such as `Signed-off-by:` or `BREAKING CHANGE:`,
+/// which git expects on a single line.
+fn is_trailer(line: &str) -> bool {
+    line.split_once(": ").is_some_and(|(token, _)| {
+        token == "BREAKING CHANGE"
+            || (!token.is_empty() && token.chars().all(|c| c.is_alphanumeric() || c == '-'))
+    })
+}
+
+/// Lines that can't be wrapped: URLs and long paths, trailers, and indented
+/// or fenced code (which is its own violation).
+fn is_unwrappable(line: &str) -> bool {
+    !line.trim().contains(char::is_whitespace)
+        || line.contains("://")
+        || is_trailer(line)
+        || line.starts_with("    ")
+        || line.starts_with('\t')
+}
+
+fn is_fence(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("```") || line.starts_with("~~~")
+}
+
+/// Subjects git writes itself, for merges, reverts and `--fixup` or
+/// `--squash` commits, which aren't checked, as in commitlint.
+const GENERATED_SUBJECTS: [&str; 9] = [
+    "Merge ",
+    "Merged ",
+    "Automatic merge",
+    "Auto-merged ",
+    "Revert \"",
+    "Reapply \"",
+    "fixup! ",
+    "squash! ",
+    "amend! ",
+];
+
+fn is_generated(message: &str) -> bool {
+    GENERATED_SUBJECTS
+        .iter()
+        .any(|prefix| message.trim_start().starts_with(prefix))
+}
+
+/// Check `message`, which should already have its comments stripped.
+pub fn check(message: &str) -> Vec<Violation> {
+    check_with_subject_limit(message, SUBJECT_LIMIT)
+}
+
+/// [`check`] with a lower subject limit, for subjects that something else
+/// will be put in front of.
+pub fn check_with_subject_limit(message: &str, subject_limit: usize) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    if is_generated(message) {
+        return violations;
+    }
+    let mut lines = message.lines();
+    let subject = lines.next().unwrap_or_default();
+
+    let length = subject.chars().count();
+    if length > subject_limit {
+        violations.push(Violation {
+            rule: Rule::SubjectLength,
+            line: 1,
+            message: format!(
+                "the subject is {} characters long, keep it to {} or fewer",
+                length, subject_limit
+            ),
+        });
+    }
+    if let Some(word) = history::description(subject).split_whitespace().next() {
+        if !is_imperative(word) {
+            violations.push(Violation {
+                rule: Rule::Imperative,
+                line: 1,
+                message: format!(
+                    "the subject should be in the imperative mood, not start with `{}`",
+                    word
+                ),
+            });
+        }
+    }
+    if subject.ends_with('.') && !subject.ends_with("...") {
+        violations.push(Violation {
+            rule: Rule::TrailingPeriod,
+            line: 1,
+            message: "the subject shouldn't end with a period".to_string(),
+        });
+    }
+
+    let mut in_code_block = false;
+    for (index, line) in lines.enumerate() {
+        let number = index + 2;
+        if number == 2 && !line.trim().is_empty() {
+            violations.push(Violation {
+                rule: Rule::BlankLine,
+                line: number,
+                message: "the subject should be followed by a blank line".to_string(),
+            });
+        }
+        if is_fence(line) {
+            if !in_code_block {
+                violations.push(Violation {
+                    rule: Rule::CodeBlock,
+                    line: number,
+                    message: "the body shouldn't contain code blocks".to_string(),
+                });
+            }
+            in_code_block = !in_code_block;
+            continue;
+        }
+        let width = line.chars().count();
+        if width > BODY_WIDTH && !in_code_block && !is_unwrappable(line) {
+            violations.push(Violation {
+                rule: Rule::BodyWidth,
+                line: number,
+                message: format!(
+                    "the line is {} characters long, wrap the body at {}",
+                    width, BODY_WIDTH
+                ),
+            });
+        }
+    }
+
+    violations
+}
+
+/// Where the text of a list item starts, e.g. 2 for `- item` or 4 for `12. item`.
+fn list_indent(line: &str) -> Option<usize> {
+    if line.starts_with("- ") || line.starts_with("* ") {
+        return Some(2);
+    }
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    (digits > 0 && line[digits..].starts_with(". ")).then_some(digits + 2)
+}
+
+/// Wrap `words` at [`BODY_WIDTH`], starting with `first` and indenting the
+/// following lines by `indent` spaces.
+fn wrap(first: &str, words: &[&str], indent: usize, wrapped: &mut Vec<String>) {
+    let mut line = first.to_string();
+    for word in words {
+        let fits = line.chars().count() + 1 + word.chars().count() <= BODY_WIDTH;
+        if line.trim().is_empty() {
+            line.push_str(word);
+        } else if fits {
+            line.push(' ');
+            line.push_str(word);
+        } else {
+            wrapped.push(line);
+            line = format!("{}{}", " ".repeat(indent), word);
+        }
+    }
+    wrapped.push(line);
+}
+
+/// Rewrap the paragraphs and list items of `body` that have lines wider than
+/// [`BODY_WIDTH`], leaving everything else as it was.
+fn rewrap(body: &str) -> String {
+    // a paragraph, or a list item with its continuation lines
+    let mut blocks: Vec<Vec<&str>> = Vec::new();
+    let mut in_code_block = false;
+    for line in body.lines() {
+        let starts_block = line.trim().is_empty()
+            || in_code_block
+            || is_fence(line)
+            || is_unwrappable(line)
+            || list_indent(line).is_some()
+            || blocks.last().is_none_or(|block| {
+                let previous = block[block.len() - 1];
+                previous.trim().is_empty() || is_fence(previous) || is_unwrappable(previous)
+            });
+        if is_fence(line) {
+            in_code_block = !in_code_block;
+        }
+        match blocks.last_mut() {
+            Some(block) if !starts_block => block.push(line),
+            _ => blocks.push(vec![line]),
+        }
+    }
+
+    let mut wrapped = Vec::new();
+    for block in blocks {
+        let too_wide = block.iter().any(|line| line.chars().count() > BODY_WIDTH);
+        if !too_wide || is_fence(block[0]) || is_unwrappable(block[0]) {
+            wrapped.extend(block.iter().map(|line| line.to_string()));
+            continue;
+        }
+        let indent = list_indent(block[0]).unwrap_or(0);
+        let words: Vec<&str> = block
+            .iter()
+            .flat_map(|line| line.split_whitespace())
+            .collect();
+        let (first, rest) = match indent {
+            0 => ("", &words[..]),
+            _ => (words[0], &words[1..]),
+        };
+        wrap(first, rest, indent, &mut wrapped);
+    }
+    wrapped.join("\n")
+}
+
+/// Fix what can be fixed without the model: drop the subject's trailing
+/// period, put a blank line after it, and rewrap the body.
+pub fn fix(message: &str) -> String {
+    if is_generated(message) {
+        return message.to_string();
+    }
+    let mut lines = message.trim().lines();
+    let subject = lines.next().unwrap_or_default().trim_end();
+    let subject = match subject.strip_suffix('.') {
+        Some(stripped) if !subject.ends_with("...") => stripped,
+        _ => subject,
+    };
+    let body = rewrap(lines.collect::<Vec<_>>().join("\n").trim_matches('\n'));
+
+    if body.trim().is_empty() {
+        format!("{}\n", subject)
+    } else {
+        format!("{}\n\n{}\n", subject, body)
+    }
+}
+
+/// [`fix`] the subject and body of a generated message.
+pub fn fix_message(message: CommitMessage) -> CommitMessage {
+    let fixed = fix(&format!("{}\n\n{}", message.subject, message.body));
+    let (subject, body) = fixed.split_once("\n\n").unwrap_or((fixed.trim_end(), ""));
+    CommitMessage {
+        subject: subject.to_string(),
+        body: body.trim_end().to_string(),
+        ..message
+    }
+}
+
+/// Lint the commit message file at `path`, fixing it in place with `fix`.
+/// Prints the violations left and returns whether there were none.
+pub fn run(path: &Path, fix: bool) -> Result<bool> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut message = strip_comments(&text);
+    if fix {
+        let fixed = self::fix(&message);
+        if fixed != message {
+            fs::write(path, &fixed)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            eprintln!("Fixed {}", path.display());
+        }
+        message = fixed;
+    }
+
+    let violations = check(&message);
+    for violation in &violations {
+        eprintln!("{}: {}", path.display(), violation);
+    }
+    Ok(violations.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(message: &str) -> Vec<Rule> {
+        check(message).iter().map(|v| v.rule).collect()
+    }
+
+    #[test]
+    fn test_check_subject() {
+        assert!(rules("Add retries to the uploader\n").is_empty());
+        assert_eq!(
+            rules("Added retries to the uploader.\n"),
+            vec![Rule::Imperative, Rule::TrailingPeriod]
+        );
+        assert_eq!(
+            rules("feat(upload): adds retries\n"),
+            vec![Rule::Imperative]
+        );
+        assert!(rules("Embed the process list in the status page\n").is_empty());
+        assert_eq!(
+            rules("Add retries to the uploader so that flaky networks don't lose work\n"),
+            vec![Rule::SubjectLength]
+        );
+        assert_eq!(
+            check_with_subject_limit("Add retries to the uploader\n", 20)[0].message,
+            "the subject is 27 characters long, keep it to 20 or fewer"
+        );
+    }
+
+    #[test]
+    fn test_generated_subjects_are_skipped() {
+        let long = "x".repeat(80);
+        for message in [
+            format!("Merge branch 'feature/{}' into main\n", long),
+            format!("Merge pull request #12 from someone/{}\n\n{}\n", long, long),
+            "Revert \"Added retries.\"\n\nThis reverts commit 3f786850e387550fdab836ed7e6dc881de23001b.\n"
+                .to_string(),
+            "fixup! Added retries.\n".to_string(),
+            "squash! Added retries.\n".to_string(),
+            format!("amend! Added retries.\n\n{}\n", "word ".repeat(20)),
+        ] {
+            assert!(check(&message).is_empty(), "{}", message);
+            assert_eq!(fix(&message), message);
+        }
+        assert_eq!(rules("Merging the uploaders.\n").len(), 2);
+    }
+
+    #[test]
+    fn test_check_body() {
+        let long = "word ".repeat(20);
+        let message = format!(
+            "Add retries\nThe uploader gave up.\n\n{}\n```\nlet x = 1;\n```\nSee https://example.com/{}\n",
+            long.trim(),
+            "a".repeat(80)
+        );
+        let violations = check(&message);
+        assert_eq!(
+            violations
+                .iter()
+                .map(|v| (v.rule, v.line))
+                .collect::<Vec<_>>(),
+            vec![
+                (Rule::BlankLine, 2),
+                (Rule::BodyWidth, 4),
+                (Rule::CodeBlock, 5)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fix_rewraps_paragraphs_and_lists() {
+        let message = format!(
+            "Add retries.\nThe uploader {}\n\n- {}\n- short item\n\nSigned-off-by: A Person <a.person@example.com> {}\n",
+            "gave up too early. ".repeat(4).trim(),
+            "retry ".repeat(15).trim(),
+            "x".repeat(40)
+        );
+        let fixed = fix(&message);
+        assert_eq!(
+            fixed,
+            format!(
+                "Add retries\n\n\
+                 The uploader gave up too early. gave up too early. gave up too early.\n\
+                 gave up too early.\n\n\
+                 - retry retry retry retry retry retry retry retry retry retry retry\n  \
+                 retry retry retry retry\n\
+                 - short item\n\n\
+                 Signed-off-by: A Person <a.person@example.com> {}\n",
+                "x".repeat(40)
+            )
+        );
+        assert!(check(&fixed).is_empty());
+    }
+
+    #[test]
+    fn test_strip_comments() {
+        assert_eq!(
+            strip_comments("Add retries\n\n# Please enter the commit message\n# ------------------------ >8 ------------------------\ndiff --git a/x b/x\n"),
+            "Add retries\n"
+        );
+    }
+}
@@ -1,16 +1,50 @@
-use async_openai::{types::CreateChatCompletionRequestArgs, Client};
-use futures::StreamExt;
+use budget::{tokenizer_for_model, TokenBudget};
+use clap::Parser;
+use cli::{Cli, HookAction};
+use commit_message::CommitMessage;
+use config::Config;
+use futures::{StreamExt, TryStreamExt};
 use lazy_static::lazy_static;
+use prompt::{Conventions, SlicePreamble};
+use provider::{CompletionChunk, CompletionProvider, ResponseFormat, TokenUsage};
+use repo::{Repo, RepoError};
 use std::{
-    io::{stdout, Write},
+    io::{stderr, Write},
     ops::{Deref, DerefMut},
     process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
 };
-use tiktoken_rs::cl100k_base;
+use tiktoken_rs::CoreBPE;
 use tokio::sync::Mutex;
 
+mod branch;
+mod budget;
+mod changes;
+mod cli;
+mod commit_message;
+mod config;
+mod conventional;
+mod diff;
+mod doctor;
+mod history;
+mod hook;
+mod ignorefile;
+mod lint;
+mod preamble;
+mod prompt;
+mod provider;
+mod redact;
+mod repo;
+mod setup;
+mod ticket;
+
+/// Set by `--verbose`.
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
-    static ref STDOUT_LOCK: Mutex<()> = Mutex::new(());
+    static ref STDERR_LOCK: Mutex<()> = Mutex::new(());
+    static ref TOKEN_USAGE: std::sync::Mutex<TokenUsage> =
+        std::sync::Mutex::new(TokenUsage::default());
 }
 
 #[derive(Clone)]
@@ -102,439 +136,560 @@ impl DerefMut for ChatCompletionRequestMessages {
     }
 }
 
-fn get_code_changes() -> Result<String, Box<dyn std::error::Error>> {
-    // todo: handle references
-    let output = Command::new("git")
-        .arg("diff")
-        .arg("--cached")
-        .arg("--")
-        .arg(".")
-        // use the user's current directory, from where the command was executed
-        .current_dir(std::env::current_dir()?)
-        .output()?;
-
-    let stdout = String::from_utf8(output.stdout)?;
-    Ok(stdout)
-}
+/// How often an answer that can't be read or breaks the configured style is
+/// sent back for correction.
+const MAX_RETRIES: usize = 2;
 
-const SYSTEM_MESSAGE: &str = "You are helping the user write a great commit message.
-======
-FORMAT
-======
-The commit message should be in the following format:
-==========
-Branch: <suggested branch name>
-~~~~~~~~~~
-Subject: <subject (50 chars or less)>
-~~~~~~~~~~
-Body: <body>
-==========
-END FORMAT
-==========
-
-Instructions:
-
-You can use the following guidelines to help them write a good commit message:
-Write a concise and informative subject line: The subject line should summarize the change in 50 characters or less. It should be written in the imperative mood (e.g., \"Add feature X\" rather than \"Added feature X\").
-Separate subject from body with a blank line: If you need to provide more context, include a message body after a blank line. This helps separate the summary from the details.
-Explain the \"what\" and \"why\" in the message body: The message body should provide context on why the change was made and any potential implications. Avoid focusing on the \"how\" since that can be deduced from the code itself.
-Use proper grammar, spelling, and punctuation: Good commit messages are well-written and easy to understand. Proper language usage helps convey the meaning effectively.
-Keep line lengths reasonable: Aim for a maximum of 72 characters per line in the message body to ensure readability across different devices and tools.
-Use bullet points or lists for multiple changes: If the commit contains several changes, organize them using bullet points or numbered lists for better readability.
-Avoid generic or ambiguous messages: Commit messages like \"bug fix\" or \"updates\" don't provide enough context. Be specific about the changes you've made.
-Don't include code in the message: The commit message should describe the change, not include the code itself. If the change is too complex to describe succinctly, consider breaking it into smaller commits.
-Proofread before committing: Double-check your commit message for clarity, accuracy, and completeness before submitting it.
-Organize the commit message to include any testing done: If you have tested the code, include the results in the commit message. If you have not tested the code, include a note that you have not tested the code.
-Humor is ok, but don't overdo it. :wink:
-Note: All of the provided code is from a single staged workspace.
-=====
-Good Output:
-Branch: <suggested branch name>
-~~~~~~~~~~
-Subject: <subject (50 chars or less)>
-~~~~~~~~~~
-Body: <body>
-=====
-Bad Output (explaining the format):
-Let me help you write a good commit message. First...
-=====
-Bad Output (giving advice):
-Here are some ways you could improve your commit message.
-=====
-Bad Output (repeating the template):
-This is a new feature that does something really cool.
-- cool thing 1
-- cool thing 2
-=====
-Bad Output (restating instructions):
-Use proper grammar, spelling, and punctuation: Good commit messages are well-written and easy to understand. Proper language usage helps convey the meaning effectively.
-=====
-Bad Output: Making things up that aren't in the diff.
-";
-
-fn create_message(message: ChatMessage) -> async_openai::types::ChatCompletionRequestMessage {
-    async_openai::types::ChatCompletionRequestMessage {
-        name: Some(message.0),
-        content: message.2,
-        role: message.1,
-    }
+/// Whether a completion is echoed to the terminal as it streams in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Output {
+    Stream,
+    Quiet,
 }
 
-async fn send_to_openai(
-    client: &Client,
+async fn send_to_provider(
+    provider: &dyn CompletionProvider,
     messages: Vec<ChatMessage>,
+    format: ResponseFormat,
+    output: Output,
 ) -> Result<String, anyhow::Error> {
-    let mut stdout = stdout().lock();
-    let result = client
-        .chat()
-        .create_stream(
-            CreateChatCompletionRequestArgs::default()
-                .messages(messages.into_iter().map(create_message).collect::<Vec<_>>())
-                .model("gpt-3.5-turbo")
-                .build()?,
-        )
-        .await;
+    // Completions may run concurrently, so hold the lock for the whole stream
+    // to keep their output from interleaving.
+    let _stderr_guard = match output {
+        Output::Stream => Some(STDERR_LOCK.lock().await),
+        Output::Quiet => None,
+    };
+    let mut stderr = stderr().lock();
+    let result = provider.stream_chat(messages.clone(), format).await;
 
     let mut collector = String::new();
+    let mut usage = None;
 
     match result {
-        Err(e) => panic!("Failed to connect to OpenAI API: {}", e),
+        Err(e) => return Err(e.context("Failed to connect to completion provider")),
 
         Ok(mut response_stream) => {
             while let Some(partial_response) = response_stream.next().await {
                 match partial_response {
                     Err(e) => {
-                        println!("Error in Chat Completion: {}", e);
+                        eprintln!("Error in Chat Completion: {}", e);
                     }
-                    Ok(response) => {
-                        let _ = stdout.write(
-                            response.choices[0]
-                                .delta
-                                .content
-                                .clone()
-                                .unwrap_or_default()
-                                .as_bytes(),
-                        )?;
+                    Ok(CompletionChunk::Delta(content)) => {
+                        if output == Output::Stream {
+                            let _ = stderr.write(content.as_bytes())?;
+                            stderr.flush()?;
+                        }
                         // add to collector
-                        collector.push_str(
-                            &response.choices[0]
-                                .delta
-                                .content
-                                .clone()
-                                .unwrap_or_default(),
-                        );
-                        stdout.flush()?;
+                        collector.push_str(&content);
+                    }
+                    Ok(CompletionChunk::Usage(reported)) => {
+                        usage = Some(reported);
                     }
                 }
             }
         }
     }
-    let _ = stdout.write(b"\n")?;
+    if output == Output::Stream {
+        let _ = stderr.write(b"\n")?;
+    }
+
+    let usage = usage.unwrap_or_else(|| provider.estimate_usage(&messages, &collector));
+    *TOKEN_USAGE.lock().unwrap() += usage;
 
     Ok(collector)
 }
 
 type ChatMessage = (String, async_openai::types::Role, String);
 
-fn load_api_key() -> Result<String, Box<dyn std::error::Error>> {
-    // first check in ~/.happycommit/config.toml
-    let happycommitconfig_checker = || -> Result<String, Box<dyn std::error::Error>> {
-        let config_path = dirs::home_dir().unwrap().join(".happycommit/config.toml");
-        let config = std::fs::read_to_string(config_path)?;
-        let config: toml::Value = toml::from_str(&config)?;
-        let openai_api_key = config.get("OPENAI_API_KEY");
-        if openai_api_key.is_none() {
-            return Err("OPENAI_API_KEY not set in ~/.happycommit/config.toml".into());
-        }
-        let result = openai_api_key.unwrap().to_string();
-        // strip quotes
-        let result = result.replace("\"", "");
-        Ok(result)
-    };
-    let dotenv_checker = || -> Result<String, Box<dyn std::error::Error>> {
-        dotenv::dotenv().ok();
-        let openai_api_key =
-            dotenv::var("OPENAI_API_KEY")?;
-        Ok(openai_api_key)
-    };
-
-    // first check happycommit config, then check dotenv - if in no places, throw
-    let result = happycommitconfig_checker();
-    if result.is_ok() {
-        return result;
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let Cli { command, options } = Cli::parse();
+    VERBOSE.store(options.verbose, Ordering::Relaxed);
+
+    let command = command.unwrap_or(cli::Command::Commit);
+    match command {
+        cli::Command::Doctor => {
+            let healthy = doctor::run(options.model.as_deref()).await;
+            std::process::exit(if healthy { 0 } else { 1 });
+        }
+        cli::Command::Lint { ref file, fix } => match lint::run(file, fix) {
+            Ok(clean) => std::process::exit(if clean { 0 } else { 1 }),
+            Err(e) => {
+                eprintln!("Error linting {}: {:#}", file.display(), e);
+                std::process::exit(1);
+            }
+        },
+        cli::Command::Completions { shell } => {
+            cli::print_completions(shell);
+            return;
+        }
+        cli::Command::Setup => {
+            if let Err(e) = setup::run() {
+                eprintln!("Setup failed: {:#}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        cli::Command::Hook {
+            action: HookAction::Install { force },
+        } => {
+            match hook::install(force) {
+                Ok(path) => eprintln!("Installed {}", path.display()),
+                Err(e) => {
+                    eprintln!("Error installing hook: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        cli::Command::Hook {
+            action: HookAction::Uninstall,
+        } => {
+            match hook::uninstall() {
+                Ok(path) => eprintln!("Removed {}", path.display()),
+                Err(e) => {
+                    eprintln!("Error removing hook: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        cli::Command::Hook {
+            action: HookAction::PrepareCommitMsg { ref source, .. },
+        } if !hook::should_fill(source.as_deref()) => return,
+        _ => {}
     }
-    let result = dotenv_checker();
-    if result.is_ok() {
-        return result;
+
+    let mut config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error loading config: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(model) = &options.model {
+        config.model = Some(model.clone());
     }
-    // throw an error
 
-    Err("OPENAI_API_KEY must be set in .env file or ~/.happycommit/config.toml".into())
-}
+    if let cli::Command::Config = command {
+        println!("# {}", Config::path().display());
+        print!("{}", config);
+        return;
+    }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    let openai_api_key = load_api_key()
-        .map_err(|e| {
-            panic!("Error loading OpenAI API key: {}", e);
-        })
-        .unwrap();
+    let provider = match provider::from_config(&config) {
+        Ok(provider) => provider,
+        Err(e) => {
+            eprintln!("Error loading completion provider: {:#}", e);
+            std::process::exit(1);
+        }
+    };
 
-    // by default, read in all the code changes since origin/master
-    // TODO: allow user to specify a different origin branch or commit
-    let code_changes = get_code_changes();
+    let source = changes::Source::from_options(&options);
+    if matches!(command, cli::Command::Commit) && !source.can_commit() {
+        eprintln!(
+            "--from and --commit describe changes that aren't about to be committed, use them with `happycommit message` or `happycommit branch`"
+        );
+        std::process::exit(1);
+    }
 
-    let code_changes = match code_changes {
+    let repo = match Repo::discover() {
+        Ok(repo) => repo,
+        Err(e) => {
+            eprintln!("Error opening the repository: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let code_changes = match repo.read(&source) {
         Ok(code_changes) => code_changes,
         Err(e) => {
-            println!("Error getting code changes: {}", e);
-            return;
+            eprintln!("Error getting code changes: {}", e);
+            std::process::exit(1);
         }
     };
 
-    let client = Client::new().with_api_key(openai_api_key);
+    // read from the full headers, before ignored files are cut down to a line
+    let notable_files = preamble::for_diff(&diff::parse(&code_changes), &repo);
+    let overview = repo.overview(&source).unwrap_or_else(|e| {
+        eprintln!("Couldn't get an overview of the changes: {:#}", e);
+        String::new()
+    });
+    let preamble = SlicePreamble {
+        overview,
+        notable_files,
+    };
 
-    // test the client connection
-    println!("Testing connection to OpenAI API...");
-    let _ = send_to_openai(
-        &client,
-        vec![
-            (
-                "Bot".to_string(),
-                async_openai::types::Role::System,
-                "You are a bot that writes commit messages.".to_string(),
-            ),
-            (
-                "Test".to_string(),
-                async_openai::types::Role::User,
-                "Hello world!".to_string(),
-            ),
-        ],
-    )
-    .await
-    .map_err(|e| panic!("Failed to connect to OpenAI API: {}", e));
-    println!("Connection successful!");
+    // lockfiles and the like are summed up rather than sent in full
+    let code_changes = match ignorefile::load(repo.workdir()) {
+        Ok(rules) => {
+            let (code_changes, ignored) = ignorefile::apply(&code_changes, &rules);
+            if !ignored.is_empty() && VERBOSE.load(Ordering::Relaxed) {
+                eprintln!("Leaving out the changes to {}", ignored.join(", "));
+            }
+            code_changes
+        }
+        Err(e) => {
+            eprintln!("Not applying {}: {:#}", ignorefile::FILE_NAME, e);
+            code_changes
+        }
+    };
 
-    let result = stream_multipart_commit_message(&client, SYSTEM_MESSAGE, code_changes.as_str())
-        .await
-        .map_err(|e| panic!("Failed to connect to OpenAI API: {}", e))
-        .unwrap();
+    // mask secrets before anything is tokenized or sent
+    let redactor = match config.redactor() {
+        Ok(redactor) => redactor,
+        Err(e) => {
+            eprintln!("Error loading config: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let (code_changes, findings) = redactor.redact(&code_changes);
+    if !findings.is_empty() {
+        eprintln!("The changes contain what looks like secrets:");
+        for finding in &findings {
+            eprintln!("  {}", finding);
+        }
+        match config.secrets {
+            redact::SecretPolicy::Abort => {
+                eprintln!("Nothing was sent. Unstage them, or set secrets = \"redact\" to mask them instead.");
+                std::process::exit(1);
+            }
+            redact::SecretPolicy::Redact => {
+                eprintln!("They were replaced with {} before sending.", redact::MASK)
+            }
+        }
+    }
 
-    let final_message = result.last().unwrap();
+    let format = config.response_format;
+    let mut conventions = config.conventions();
+    if config.history_commits() > 0 {
+        match repo.recent_messages(config.history_commits()) {
+            Ok(messages) => conventions.history = history::infer(&messages),
+            Err(e) => eprintln!("Couldn't read the commit history, skipping it: {}", e),
+        }
+    }
+    // read before any new branch is created, since that one is named after the message
+    let current_branch = match repo.current_branch() {
+        Ok(current) => Some(current),
+        Err(RepoError::DetachedHead) => None,
+        Err(e) => {
+            eprintln!("Couldn't read the current branch: {}", e);
+            None
+        }
+    };
+    let tickets = match config.ticket_pattern() {
+        Ok(pattern) => current_branch
+            .as_deref()
+            .map(|current| ticket::extract(&pattern, current))
+            .unwrap_or_default(),
+        Err(e) => {
+            eprintln!("Not looking for ticket IDs: {:#}", e);
+            Vec::new()
+        }
+    };
+    let budget = TokenBudget::for_model(provider.model(), config.context_window, config.max_tokens);
+    // a context window too small for the prompt is a config problem, not a provider one
+    let system_message = prompt::system_message(format, &conventions);
+    if let Err(e) = budget.code_tokens(&tokenizer_for_model(provider.model()), &[&system_message]) {
+        eprintln!(
+            "Error: {:#}. Set context_window or max_tokens in the config.",
+            e
+        );
+        std::process::exit(1);
+    }
+    let final_message = match stream_multipart_commit_message(
+        provider.as_ref(),
+        &conventions,
+        &preamble,
+        code_changes.as_str(),
+        format,
+        &budget,
+        config.concurrency(),
+    )
+    .await
+    {
+        Ok(final_message) => final_message,
+        Err(e) => {
+            eprintln!(
+                "Error getting a commit message from {}: {:#}",
+                provider.model(),
+                e
+            );
+            std::process::exit(1);
+        }
+    };
 
-    println!("Final Commit message:\n{}\n\n", final_message.as_str());
+    eprintln!("Final Commit message:\n{}\n\n", final_message.as_str());
+
+    let validate = |text: &str, attempt: usize| {
+        let message = match format {
+            ResponseFormat::Json => CommitMessage::from_json(text)?,
+            ResponseFormat::Text => CommitMessage::parse(text).ok_or_else(|| {
+                anyhow::anyhow!("it doesn't follow the Branch/Subject/Body format")
+            })?,
+        };
+        let message = match &conventions.conventional_types {
+            Some(types) => conventional::check(message, types)?,
+            None => message,
+        };
+        let prefix = |message| match config.ticket_placement {
+            ticket::Placement::Subject => ticket::prefix_subject(message, &tickets),
+            _ => message,
+        };
+        if !config.lint() {
+            return Ok(prefix(message));
+        }
 
-    let create_messages = |query: String| -> Vec<ChatMessage> {
-        // assert that query must be one of "Branch", "Subject", or "Body"
-        assert!(query == "Branch" || query == "Subject" || query == "Body");
-        vec![
-            ("CommitQueryBot".to_string(), async_openai::types::Role::System, "The CommitMesssageProvider will provide text using the following format, then request either \"Branch\", \"Subject\", or \"Body\"
-            You will provide back the user the exact contents of what is requested. This will be done without any additiona prose or formatting.
-            Here is the format that the user will provide (note, the user may not follow this format exactly, you will need to interpolate the text into the correct place):
-            ```
-            Branch: <suggested branch name>
-            ~~~~~~~~~~
-            Subject: <subject>
-            ~~~~~~~~~~
-            Body: <body>
-            ```
-
-            Example 1:
-            ```
-            Provide Branch for:
-
-            Branch: feature/1234
-            ~~~~~~~~~~
-            Subject: Add a new feature
-            ~~~~~~~~~~
-            Body: This is a new feature that does something really cool.
-            - cool thing 1
-            - cool thing 2
-
-            Good Output: feature/1234
-            Bad Output: \"feature/1234\"
-            Bad Output: \"Branch: feature/1234\"
-            ```
-
-            Example 2:
-            ```
-            Provide Subject for:
-
-            Branch: feature/1234
-            ~~~~~~~~~~
-            Subject: Add a new feature
-            ~~~~~~~~~~
-            Body: This is a new feature that does something really cool.
-            - cool thing 1
-            - cool thing 2
-
-            Good Output: Add a new feature
-            Bad Output: \"Add a new feature\"
-            Bad Output: \"Subject: Add a new feature\"
-            ```
-
-            Example 3:
-            ```
-            Provide Body for:
-
-            Branch: feature/1234
-            ~~~~~~~~~~
-            Subject: Add a new feature
-            ~~~~~~~~~~
-            Body: This is a new feature that does something really cool.
-            - cool thing 1
-            - cool thing 2
-
-            Good Output: This is a new feature that does something really cool.
-            - cool thing 1
-            - cool thing 2
-            Bad Output: \"This is a new feature that does something really cool.\"
-            Bad Output: \"Body: This is a new feature that does something really cool.\"
-            ```".to_string()),
-            ("CommitMessageProvider".to_string(), async_openai::types::Role::Assistant, final_message.to_string()),
-            ("User".to_string(), async_openai::types::Role::User, format!("Provide {} for commit message ^", query)),
-        ]
+        // rewrapping and the like are fixed here; the rest goes back to the
+        // model, until it runs out of retries and the user gets to fix it
+        let message = lint::fix_message(message);
+        // the model is only told about what it wrote, so the ticket prefix
+        // goes on afterwards and its subject has to leave room for it
+        let prefix_length =
+            prefix(message.clone()).subject.chars().count() - message.subject.chars().count();
+        let violations = lint::check_with_subject_limit(
+            &message.to_git_message(None),
+            lint::SUBJECT_LIMIT.saturating_sub(prefix_length),
+        );
+        if violations.is_empty() || attempt == MAX_RETRIES {
+            for violation in &violations {
+                eprintln!("Warning: {}", violation);
+            }
+            return Ok(prefix(message));
+        }
+        let problems: Vec<String> = violations.iter().map(|v| v.message.clone()).collect();
+        Err(anyhow::anyhow!("{}", problems.join("; ")))
     };
 
-    // switch branch using git switch -c <branch>
-    let branch = send_to_openai(&client, create_messages("Branch".to_string()))
-        .await
-        .unwrap();
-    let branch = branch.trim();
-    println!("Setting Branch: {}", branch);
-    let _ = Command::new("git")
-        .arg("switch")
-        .arg("-c")
-        .arg(branch)
-        .output()
-        .expect("Failed to set branch");
+    // only ask the model to pick apart text answers; JSON ones get corrected instead
+    let answer = match format {
+        ResponseFormat::Text if CommitMessage::parse(&final_message).is_none() => {
+            eprintln!("Couldn't parse the commit message, asking the model to pick it apart...");
+            match commit_message::query(provider.as_ref(), &final_message).await {
+                Ok(message) => message.to_text(),
+                Err(e) => {
+                    eprintln!(
+                        "Error getting a commit message from {}: {:#}",
+                        provider.model(),
+                        e
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => final_message,
+    };
 
-    // open the commit message in the editor, with the subject and body filled in
-    let subject = send_to_openai(&client, create_messages("Subject".to_string()))
-        .await
-        .unwrap();
-    let body = send_to_openai(&client, create_messages("Body".to_string()))
-        .await
-        .unwrap();
+    let message = match commit_message::with_retries(
+        provider.as_ref(),
+        &system_message,
+        format,
+        &answer,
+        MAX_RETRIES,
+        validate,
+    )
+    .await
+    {
+        Ok(message) => message,
+        Err(e) => {
+            eprintln!("Error getting a valid commit message: {:#}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let commit_message = format!("{}
+    let footer: Vec<String> = [
+        ticket::trailer(&message, &tickets, config.ticket_placement),
+        config.footer(provider.model()),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    let footer = footer.join("\n");
+    let commit_message = message.to_git_message(Some(footer.as_str()));
+
+    match command {
+        cli::Command::Message { json } => {
+            if json {
+                println!("{}", serde_json::to_string_pretty(&message).unwrap());
+            } else {
+                print!("{}", commit_message);
+            }
+            print_usage(provider.model());
+            return;
+        }
+        cli::Command::Branch => {
+            if let Err(e) = switch_branch(&config, &message, tickets.first(), options.dry_run) {
+                eprintln!("Error switching branch: {:#}", e);
+                std::process::exit(1);
+            }
+            print_usage(provider.model());
+            return;
+        }
+        // git is in the middle of a commit, so just hand it the message
+        cli::Command::Hook {
+            action: HookAction::PrepareCommitMsg { ref file, .. },
+        } => {
+            if let Err(e) = hook::fill(file, &commit_message) {
+                eprintln!("Error writing commit message: {}", e);
+            }
+            print_usage(provider.model());
+            return;
+        }
+        _ => {}
+    }
 
+    // asked before switching, so declining leaves the user where they were
+    if source == changes::Source::All && !options.dry_run {
+        let stage = options.yes
+            || cli::confirm("Stage all changes to tracked files and commit them?").unwrap_or(false);
+        if !stage {
+            eprintln!("Nothing was staged or committed.");
+            print_usage(provider.model());
+            return;
+        }
+        if let Err(e) = changes::stage_all() {
+            eprintln!("Error staging changes: {:#}", e);
+            std::process::exit(1);
+        }
+    }
 
-    {}
+    // amending keeps the commit where it is
+    let protected_branches = config.protected_branches();
+    if !options.no_branch
+        && !options.amend
+        && branch::should_create(
+            config.create_branch,
+            current_branch.as_deref(),
+            &protected_branches,
+        )
+    {
+        if let Err(e) = switch_branch(&config, &message, tickets.first(), options.dry_run) {
+            // a commit the branch was meant to keep off a protected branch
+            // doesn't go there just because switching failed
+            if branch::is_protected(current_branch.as_deref(), &protected_branches) {
+                eprintln!(
+                    "Not committing on {}: {:#}",
+                    current_branch.as_deref().unwrap_or("a detached HEAD"),
+                    e
+                );
+                std::process::exit(1);
+            }
+            eprintln!("Staying on the current branch: {:#}", e);
+        }
+    }
 
-    ~~~~~~~~~~
-    This commit message was generated by HappyCommit. Try it in your project today!
-    Check it out at https://github.com/jackbackes/happycommit
-    ", subject, body);
+    if options.dry_run {
+        print!("Would commit with message:\n{}", commit_message);
+        print_usage(provider.model());
+        return;
+    }
 
+    // open the commit message in the editor, with the subject and body filled in
     let mut commit_file = tempfile::NamedTempFile::new().expect("Failed to create temporary file");
-    commit_message.split('\n').for_each(|line| {
-        let _ = writeln!(commit_file, "{}", line);
-    });
+    let _ = commit_file.write_all(commit_message.as_bytes());
 
     // flush and close the file
     let _ = commit_file.flush();
 
-    let status = Command::new("git")
+    let mut git_commit = Command::new("git");
+    git_commit
         .arg("commit")
         .arg("--file")
-        .arg(commit_file.path())
-        .arg("--edit")
+        .arg(commit_file.path());
+    if !options.yes {
+        git_commit.arg("--edit");
+    }
+    if options.amend {
+        git_commit.arg("--amend");
+    }
+    let status = git_commit
         .stdin(Stdio::inherit())
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
         .status()
         .expect("Failed to commit");
 
+    print_usage(provider.model());
+
     if status.success() {
-        println!("Commit successful! Thanks for using happycommit!");
+        eprintln!("Commit successful! Thanks for using happycommit!");
     } else {
         eprintln!("Commit failed. Please check the error message and try again.");
     }
 }
 
+/// Switch to the branch named by the config's template. Without a template,
+/// names carry the ticket ID from the current branch if there is one. Names
+/// git rejects are replaced with one it accepts.
+fn switch_branch(
+    config: &Config,
+    message: &CommitMessage,
+    ticket: Option<&String>,
+    dry_run: bool,
+) -> Result<String> {
+    let ticket = ticket.map(String::as_str);
+    let name = match (&config.branch_template, ticket) {
+        (Some(template), _) => branch::render(template, message, ticket),
+        (None, Some(_)) => branch::render(ticket::BRANCH_TEMPLATE, message, ticket),
+        (None, None) => message.branch.clone(),
+    };
+    let valid = branch::valid_name(&name, message, ticket);
+    if valid != name {
+        eprintln!(
+            "\"{}\" is not a valid branch name, using {} instead",
+            name, valid
+        );
+    }
+    branch::switch(&valid, config.branch_collision, dry_run)
+}
+
+fn print_usage(model: &str) {
+    let usage = *TOKEN_USAGE.lock().unwrap();
+    eprintln!(
+        "Used {} tokens ({} prompt, {} completion) with {}",
+        usage.total(),
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        model
+    );
+}
+
 use anyhow::Result;
 
-#[allow(clippy::needless_borrow)]
+/// Write a commit message for `code_changes`, however large.
+///
+/// A diff that fits in one slice is sent as is. Larger diffs are summarized
+/// slice by slice, with up to `concurrency` requests in flight (map), and the
+/// per-slice messages are then merged into one (reduce). Only the final
+/// message is streamed to the terminal; the per-slice messages are printed
+/// whole as they complete.
+///
+/// `preamble` goes in front of every slice, so that each one carries the
+/// overall picture along with its part of the diff. It's cut down to a share
+/// of the budget, so that it never crowds out the diff itself.
 async fn stream_multipart_commit_message(
-    client: &Client,
-    initial_prompt: &str,
+    provider: &dyn CompletionProvider,
+    conventions: &Conventions,
+    preamble: &SlicePreamble,
     code_changes: &str,
-) -> Result<Vec<String>, anyhow::Error> {
-    let tokenizer = cl100k_base().unwrap();
-    let max_tokens = 3500;
-    let tokenized_iter = tokenizer.split_by_token_iter(code_changes, true);
-
-    let overlap = 200;
-    let mut split_code_changes = Vec::new();
-
-    let initial_prompt_tokens = tokenizer
-        .split_by_token(initial_prompt, true)?
-        .len();
-
-    let max_code_tokens = max_tokens - initial_prompt_tokens;
-
-    let mut tokenized_iter = tokenized_iter.enumerate().peekable();
-
-    while tokenized_iter.peek().is_some() {
-        let mut current_split = Vec::new();
-
-        // Loop until current_split_len reaches max_code_tokens or all tokens are processed
-        while let Some((i, token_res)) = tokenized_iter.peek_mut() {
-            // If the token is an error, then return an error
-            if token_res.is_err() {
-                return Err(anyhow::anyhow!(
-                    "Error tokenizing code changes at index {}: {:?}",
-                    i,
-                    split_code_changes
-                ));
-            }
-
-            current_split.push(token_res.as_ref().unwrap().clone());
-
-            // Check if we have reached max_code_tokens or if we are at the last token
-            if current_split.len() >= max_code_tokens {
-                // We've reached max_code_tokens, let's go back by "overlap" tokens if possible
-                let tokens_to_skip = current_split.len() - overlap;
-
-                println!(
-                    "Finished current split. Going from {} to {} tokens",
-                    current_split.len(),
-                    tokens_to_skip
-                );
-
-                // Skip tokens_to_skip tokens in tokenized_iter for the next iteration
-                tokenized_iter.nth(tokens_to_skip - 1);
-
-                // Break out of the loop
-                break;
-            } else if tokenized_iter.peek().is_none() {
-                // Consume the last token and exit the loop
-                tokenized_iter.next();
-                break;
-            } else {
-                // Consume the token and continue the loop
-                tokenized_iter.next();
-            }
-        }
-
-        // Add current_split to split_code_changes
-        split_code_changes.push(current_split);
-    }
-
-    let mut iteration_count = 0;
+    format: ResponseFormat,
+    budget: &TokenBudget,
+    concurrency: usize,
+) -> Result<String, anyhow::Error> {
+    let initial_prompt = prompt::system_message(format, conventions);
+    let initial_prompt = initial_prompt.as_str();
+    let tokenizer = tokenizer_for_model(provider.model());
+    let preamble = preamble.render(
+        &tokenizer,
+        TokenBudget::preamble_tokens(budget.code_tokens(&tokenizer, &[initial_prompt])?),
+    );
+    let max_code_tokens = budget.code_tokens(&tokenizer, &[initial_prompt, &preamble])?;
+    let overlap = TokenBudget::overlap(max_code_tokens);
+
+    let split_code_changes = diff::split(code_changes, &tokenizer, max_code_tokens, overlap)?;
     let split_code_changes_len = split_code_changes.len();
 
-    // Process each split_code_changes using the provided client and model, and concatenate the results
-    let mut commit_messages: Vec<String> = Vec::new();
-    for split_code_change_slice in split_code_changes {
-        iteration_count += 1;
-        println!("Iteration {}", iteration_count);
-        // Join the tokens back into a string. This is the slice of code changes we will send to the api.
-        let code_change_slice = split_code_change_slice.join("");
-
-        // Join the prompt, the code change slice, and the previous commit message if it exists.
-        let mut messages = vec![
+    let slice_messages = |code_change_slice: String| {
+        vec![
             (
                 "GitCommitBot".to_string(),
                 async_openai::types::Role::System,
@@ -543,49 +698,281 @@ async fn stream_multipart_commit_message(
             (
                 "User".to_string(),
                 async_openai::types::Role::User,
-                code_change_slice.to_string(),
+                format!("{}{}", preamble, code_change_slice),
             ),
-        ];
-        // add the most recent commit message if it exists
-        if let Some(commit_message) = commit_messages.last() {
-            let message = "Previous commit message is as follows.
-            Take this commit message and modify it to include the additional code snippet (above) provided by the user.
-            ======
-            FORMAT
-            ======
-            The commit message should be in the following format:
-            ==========
-            Branch: <suggested branch name>
-            ~~~~~~~~~~
-            Subject: <subject (50 chars or less)>
-            ~~~~~~~~~~
-            Body: <body>
-            ==========
-            END FORMAT
-            ==========
-            \n".to_string() + commit_message;
-            messages.push((
-                "PreviousCommitProvider".to_string(),
-                async_openai::types::Role::User,
-                message,
-            ));
-        }
+        ]
+    };
 
-        println!(
-            "Sending slice #{} of {} to OpenAI API...",
-            iteration_count, split_code_changes_len
+    if split_code_changes_len <= 1 {
+        let code_change_slice = split_code_changes.into_iter().next().unwrap_or_default();
+        eprintln!("Sending changes to {}...", provider.model());
+        return send_to_provider(
+            provider,
+            slice_messages(code_change_slice),
+            format,
+            Output::Stream,
+        )
+        .await;
+    }
+
+    // map: write a commit message for each slice on its own
+    let commit_messages: Vec<String> =
+        futures::stream::iter(split_code_changes.into_iter().enumerate())
+            .map(|(i, code_change_slice)| {
+                let messages = slice_messages(code_change_slice);
+                async move {
+                    eprintln!(
+                        "Sending slice #{} of {} to {}...",
+                        i + 1,
+                        split_code_changes_len,
+                        provider.model()
+                    );
+                    let result =
+                        send_to_provider(provider, messages, format, Output::Quiet).await?;
+
+                    let _stderr_guard = STDERR_LOCK.lock().await;
+                    if VERBOSE.load(Ordering::Relaxed) {
+                        eprintln!("Commit message for slice #{}:\n{}\n", i + 1, result.trim());
+                    } else {
+                        eprintln!("Slice #{} of {} done", i + 1, split_code_changes_len);
+                    }
+                    Ok::<_, anyhow::Error>(result)
+                }
+            })
+            .buffered(concurrency.max(1))
+            .try_collect()
+            .await?;
+
+    // reduce: merge the per-slice messages, in rounds if they don't all fit at once
+    let reduce_message = prompt::reduce_message(format, conventions);
+    let max_summary_tokens = budget.code_tokens(&tokenizer, &[initial_prompt, &reduce_message])?;
+    let mut commit_messages = commit_messages;
+    let mut round = 0;
+    while commit_messages.len() > 1 {
+        round += 1;
+        let groups = group_by_tokens(commit_messages, &tokenizer, max_summary_tokens);
+        let output = if groups.len() == 1 {
+            Output::Stream
+        } else {
+            Output::Quiet
+        };
+        eprintln!(
+            "Merging commit messages (round {}, {} request(s))...",
+            round,
+            groups.len()
         );
-        let result = send_to_openai(&client, messages).await?;
-        println!("Received response from OpenAI API");
 
-        commit_messages.push(result);
+        commit_messages = futures::stream::iter(groups)
+            .map(|group| {
+                let messages = vec![
+                    (
+                        "GitCommitBot".to_string(),
+                        async_openai::types::Role::System,
+                        initial_prompt.to_string(),
+                    ),
+                    (
+                        "SliceSummaries".to_string(),
+                        async_openai::types::Role::User,
+                        reduce_message.clone() + &group.join("\n=====\n"),
+                    ),
+                ];
+                send_to_provider(provider, messages, format, output)
+            })
+            .buffered(concurrency.max(1))
+            .try_collect()
+            .await?;
     }
 
-    Ok(commit_messages)
+    Ok(commit_messages.pop().unwrap_or_default())
+}
+
+/// Pack `messages` into groups that fit in `max_tokens`, keeping at least
+/// two messages per group so that every reduce round makes progress.
+fn group_by_tokens(
+    messages: Vec<String>,
+    tokenizer: &CoreBPE,
+    max_tokens: usize,
+) -> Vec<Vec<String>> {
+    let mut groups: Vec<Vec<String>> = Vec::new();
+    let mut current: Vec<String> = Vec::new();
+    let mut current_tokens = 0;
+
+    for message in messages {
+        let tokens = tokenizer.encode_with_special_tokens(&message).len();
+        if current.len() >= 2 && current_tokens + tokens > max_tokens {
+            groups.push(std::mem::take(&mut current));
+            current_tokens = 0;
+        }
+        current.push(message);
+        current_tokens += tokens;
+    }
+
+    // a lone leftover message joins the previous group rather than going round again
+    match (groups.last_mut(), current.len()) {
+        (Some(last), 1) => last.append(&mut current),
+        _ if !current.is_empty() => groups.push(current),
+        _ => {}
+    }
+
+    groups
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use provider::CompletionStream;
+
+    use std::sync::atomic::AtomicUsize;
+
+    /// A provider that always answers with the same canned response.
+    struct CannedProvider(&'static str, AtomicUsize);
+
+    impl CannedProvider {
+        fn new(response: &'static str) -> Self {
+            CannedProvider(response, AtomicUsize::new(0))
+        }
+
+        fn calls(&self) -> usize {
+            self.1.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl CompletionProvider for CannedProvider {
+        fn model(&self) -> &str {
+            "canned"
+        }
+
+        async fn stream_chat(
+            &self,
+            _messages: Vec<ChatMessage>,
+            _format: ResponseFormat,
+        ) -> Result<CompletionStream> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            let chunks = self
+                .0
+                .split_inclusive(' ')
+                .map(|chunk| Ok(CompletionChunk::Delta(chunk.to_string())))
+                .collect::<Vec<_>>();
+            Ok(Box::pin(futures::stream::iter(chunks)))
+        }
+
+        async fn list_models(&self) -> Result<Vec<String>> {
+            Ok(vec!["canned".to_string()])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multipart_commit_message_with_provider() {
+        let provider = CannedProvider::new("Branch: fix/typo ~~~~~~~~~~ Subject: Fix typo");
+        let budget = TokenBudget::for_model("canned", Some(4096), None);
+        let message = stream_multipart_commit_message(
+            &provider,
+            &Conventions::default(),
+            &SlicePreamble::default(),
+            "+ hello",
+            ResponseFormat::Text,
+            &budget,
+            4,
+        )
+        .await
+        .unwrap();
+        assert_eq!(message, "Branch: fix/typo ~~~~~~~~~~ Subject: Fix typo");
+        assert_eq!(provider.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_large_diff_is_mapped_then_reduced() {
+        let provider = CannedProvider::new("Branch: feature/many ~~~~~~~~~~ Subject: Touch files");
+        let code_changes = (0..20)
+            .map(|i| {
+                format!(
+                    "diff --git a/f{i}.rs b/f{i}.rs\n--- a/f{i}.rs\n+++ b/f{i}.rs\n@@ -1 +1 @@\n-let a = {i};\n+let b = {i};\n"
+                )
+            })
+            .collect::<String>();
+
+        // leave room for only a few files per slice
+        let tokenizer = tiktoken_rs::cl100k_base().unwrap();
+        let system_message = prompt::system_message(ResponseFormat::Text, &Conventions::default());
+        let prompt_tokens = tokenizer.encode_with_special_tokens(&system_message).len();
+        let budget = TokenBudget::for_model("canned", Some(prompt_tokens + 600), Some(200));
+
+        let message = stream_multipart_commit_message(
+            &provider,
+            &Conventions::default(),
+            &SlicePreamble::default(),
+            &code_changes,
+            ResponseFormat::Text,
+            &budget,
+            3,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            message,
+            "Branch: feature/many ~~~~~~~~~~ Subject: Touch files"
+        );
+
+        let tokenizer = tokenizer_for_model("canned");
+        let max_code_tokens = budget.code_tokens(&tokenizer, &[&system_message]).unwrap();
+        let slices = diff::split(&code_changes, &tokenizer, max_code_tokens, 0).unwrap();
+        assert!(slices.len() > 1);
+        assert!(provider.calls() > slices.len());
+    }
+
+    #[tokio::test]
+    async fn test_last_retry_can_accept() {
+        let provider = CannedProvider::new("Branch: fix/typo ~~~~~~~~~~ Subject: Fix typo");
+        let seen = std::sync::Mutex::new(Vec::new());
+        let message = commit_message::with_retries(
+            &provider,
+            "system",
+            ResponseFormat::Text,
+            "not a commit message",
+            2,
+            |text, attempt| {
+                seen.lock().unwrap().push(attempt);
+                match CommitMessage::parse(text) {
+                    Some(message) if attempt == 2 => Ok(message),
+                    _ => Err(anyhow::anyhow!("still wrong")),
+                }
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(message.subject, "Fix typo");
+        assert_eq!(*seen.lock().unwrap(), vec![0, 1, 2]);
+        assert_eq!(provider.calls(), 2);
+    }
+
+    #[test]
+    fn test_large_overview_leaves_room_for_the_diff() {
+        let tokenizer = tokenizer_for_model("gpt-3.5-turbo");
+        let budget = TokenBudget::for_model("gpt-3.5-turbo", Some(4096), None);
+        let system_message = prompt::system_message(ResponseFormat::Text, &Conventions::default());
+        let overview: String = (0..150)
+            .map(|i| format!(" src/module{i}/file{i}.rs | 12 ++++++------\n"))
+            .collect::<String>()
+            + " 150 files changed, 900 insertions(+), 900 deletions(-)\n";
+        let preamble = SlicePreamble {
+            overview,
+            notable_files: String::new(),
+        };
+
+        let code_tokens = budget.code_tokens(&tokenizer, &[&system_message]).unwrap();
+        let rendered = preamble.render(&tokenizer, TokenBudget::preamble_tokens(code_tokens));
+        assert!(rendered.contains(" src/module0/file0.rs | 12 ++++++------\n"));
+        assert!(rendered.contains(" more lines\n"));
+
+        let left = budget
+            .code_tokens(&tokenizer, &[&system_message, &rendered])
+            .unwrap();
+        // the preamble is counted as a message of its own, with 4 tokens of framing
+        assert!(left + 4 >= code_tokens - TokenBudget::preamble_tokens(code_tokens));
+    }
+
     #[test]
     fn test_tokenizer() {
         let tokenizer = tiktoken_rs::cl100k_base().unwrap();
@@ -0,0 +1,234 @@
+//! A summary of the changes a diff shows badly or not at all.
+//!
+//! Added, deleted, renamed and binary files and mode changes are easy to miss
+//! in a diff, and binary files have no content to show. The summary lists
+//! them, with sizes where git has them, and goes in front of every slice so
+//! the model knows about them whichever slice it is looking at.
+
+use std::collections::HashMap;
+
+use crate::{diff::FileDiff, repo::Repo};
+
+/// Files listed before the rest are only counted, to keep the summary small.
+const MAX_FILES: usize = 50;
+
+/// The hash git uses for "no file" in `index` lines.
+fn is_null_blob(blob: &str) -> bool {
+    blob.chars().all(|c| c == '0')
+}
+
+/// What the diff header says about one file, beyond its hunks.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileSummary {
+    pub path: String,
+    pub added: bool,
+    pub deleted: bool,
+    pub binary: bool,
+    /// The old path and similarity in percent, for renames.
+    pub renamed_from: Option<(String, u8)>,
+    pub old_mode: Option<String>,
+    pub new_mode: Option<String>,
+    pub old_blob: Option<String>,
+    pub new_blob: Option<String>,
+}
+
+impl FileSummary {
+    fn from_diff(file: &FileDiff) -> Option<FileSummary> {
+        let mut summary = FileSummary {
+            path: file.path()?.to_string(),
+            ..Default::default()
+        };
+        let mut rename_from = None;
+        let mut similarity = 0;
+
+        for line in file.header.lines() {
+            if line.starts_with("new file mode ") {
+                summary.added = true;
+            } else if line.starts_with("deleted file mode ") {
+                summary.deleted = true;
+            } else if line.starts_with("Binary files ") || line == "GIT binary patch" {
+                summary.binary = true;
+            } else if let Some(from) = line.strip_prefix("rename from ") {
+                rename_from = Some(from.to_string());
+            } else if let Some(percent) = line.strip_prefix("similarity index ") {
+                similarity = percent.trim_end_matches('%').parse().unwrap_or(0);
+            } else if let Some(mode) = line.strip_prefix("old mode ") {
+                summary.old_mode = Some(mode.to_string());
+            } else if let Some(mode) = line.strip_prefix("new mode ") {
+                summary.new_mode = Some(mode.to_string());
+            } else if let Some(blobs) = line.strip_prefix("index ") {
+                let blobs = blobs.split_whitespace().next().unwrap_or_default();
+                if let Some((old, new)) = blobs.split_once("..") {
+                    summary.old_blob = Some(old.to_string()).filter(|blob| !is_null_blob(blob));
+                    summary.new_blob = Some(new.to_string()).filter(|blob| !is_null_blob(blob));
+                }
+            }
+        }
+        summary.renamed_from = rename_from.map(|from| (from, similarity));
+        Some(summary)
+    }
+
+    /// Whether there's anything to say that the hunks don't.
+    fn is_notable(&self) -> bool {
+        self.added
+            || self.deleted
+            || self.binary
+            || self.renamed_from.is_some()
+            || self.old_mode.is_some()
+    }
+
+    fn describe(&self, sizes: &HashMap<String, u64>) -> String {
+        let size = |blob: &Option<String>| blob.as_ref().and_then(|blob| sizes.get(blob)).copied();
+        let kind = if self.binary { "binary " } else { "" };
+
+        let mut description = if self.added {
+            format!("added {}{}", kind, self.path)
+        } else if self.deleted {
+            format!("deleted {}{}", kind, self.path)
+        } else if let Some((from, similarity)) = &self.renamed_from {
+            format!(
+                "renamed {}{} to {} ({}% similar)",
+                kind, from, self.path, similarity
+            )
+        } else if self.binary {
+            format!("changed binary {}", self.path)
+        } else {
+            format!("changed {}", self.path)
+        };
+
+        let sizes = match (size(&self.old_blob), size(&self.new_blob)) {
+            (Some(old), Some(new)) if self.binary => {
+                Some(format!("{} -> {}", human_size(old), human_size(new)))
+            }
+            (None, Some(new)) if self.added => Some(human_size(new)),
+            (Some(old), None) if self.deleted => Some(human_size(old)),
+            _ => None,
+        };
+        if let Some(sizes) = sizes {
+            description.push_str(&format!(", {}", sizes));
+        }
+        if let (Some(old), Some(new)) = (&self.old_mode, &self.new_mode) {
+            description.push_str(&format!(", mode {} -> {}", old, new));
+        }
+        description
+    }
+}
+
+fn human_size(bytes: u64) -> String {
+    match bytes {
+        bytes if bytes < 1024 => format!("{} B", bytes),
+        bytes if bytes < 1024 * 1024 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        bytes => format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+/// The sizes of `blobs`, for those in `repo`. Blobs of unstaged files only
+/// exist in the work tree, so they're missing.
+fn blob_sizes(blobs: &[&str], repo: &Repo) -> HashMap<String, u64> {
+    blobs
+        .iter()
+        .filter_map(|blob| Some((blob.to_string(), repo.blob_size(blob)?)))
+        .collect()
+}
+
+/// The notable files in `files`.
+pub fn summarize(files: &[FileDiff]) -> Vec<FileSummary> {
+    files
+        .iter()
+        .filter_map(FileSummary::from_diff)
+        .filter(FileSummary::is_notable)
+        .collect()
+}
+
+/// The summary as sent to the model, or nothing if no file is notable.
+pub fn render(summaries: &[FileSummary], sizes: &HashMap<String, u64>) -> String {
+    if summaries.is_empty() {
+        return String::new();
+    }
+    let mut text = String::from("Files added, deleted, renamed, binary or with a new mode:\n");
+    for summary in summaries.iter().take(MAX_FILES) {
+        text.push_str(&format!("- {}\n", summary.describe(sizes)));
+    }
+    if summaries.len() > MAX_FILES {
+        text.push_str(&format!("- and {} more\n", summaries.len() - MAX_FILES));
+    }
+    text.push('\n');
+    text
+}
+
+/// The summary for the `files` of a diff, with blob sizes looked up in `repo`.
+pub fn for_diff(files: &[FileDiff], repo: &Repo) -> String {
+    let summaries = summarize(files);
+    let blobs: Vec<&str> = summaries
+        .iter()
+        .flat_map(|summary| [&summary.old_blob, &summary.new_blob])
+        .flatten()
+        .map(String::as_str)
+        .collect();
+    render(&summaries, &blob_sizes(&blobs, repo))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff;
+
+    const DIFF: &str = "diff --git a/logo.png b/logo.png
+new file mode 100644
+index 0000000..aaaaaaa
+Binary files /dev/null and b/logo.png differ
+diff --git a/old.txt b/old.txt
+deleted file mode 100644
+index bbbbbbb..0000000
+--- a/old.txt
++++ /dev/null
+@@ -1 +0,0 @@
+-gone
+diff --git a/src/a.rs b/src/b.rs
+similarity index 92%
+rename from src/a.rs
+rename to src/b.rs
+index ccccccc..ddddddd 100644
+diff --git a/run.sh b/run.sh
+old mode 100644
+new mode 100755
+diff --git a/assets/font.woff b/assets/font.woff
+index eeeeeee..fffffff 100644
+Binary files a/assets/font.woff and b/assets/font.woff differ
+diff --git a/src/main.rs b/src/main.rs
+index 1111111..2222222 100644
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1 +1 @@
+-a
++b
+";
+
+    #[test]
+    fn test_render_notable_files() {
+        let summaries = summarize(&diff::parse(DIFF));
+        let sizes = HashMap::from([
+            ("aaaaaaa".to_string(), 2048),
+            ("bbbbbbb".to_string(), 5),
+            ("eeeeeee".to_string(), 3 * 1024 * 1024),
+            ("fffffff".to_string(), 1024 * 1024),
+        ]);
+        assert_eq!(
+            render(&summaries, &sizes),
+            "Files added, deleted, renamed, binary or with a new mode:
+- added binary logo.png, 2.0 KB
+- deleted old.txt, 5 B
+- renamed src/a.rs to src/b.rs (92% similar)
+- changed run.sh, mode 100644 -> 100755
+- changed binary assets/font.woff, 3.0 MB -> 1.0 MB
+
+"
+        );
+    }
+
+    #[test]
+    fn test_nothing_notable() {
+        let files = diff::parse(DIFF);
+        assert_eq!(render(&summarize(&files[5..]), &HashMap::new()), "");
+    }
+}
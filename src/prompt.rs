@@ -0,0 +1,343 @@
+//! Prompts sent to the model.
+//!
+//! The instructions are the same whichever [`ResponseFormat`] is requested;
+//! only the description of the expected answer changes. [`Conventions`] add
+//! the project's own rules on top.
+
+use tiktoken_rs::CoreBPE;
+
+use crate::{history::RepoStyle, provider::ResponseFormat};
+
+/// Project-specific rules for the message, on top of the general instructions.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Conventions {
+    /// Set when subjects must be Conventional Commits headers using one of
+    /// these types.
+    pub conventional_types: Option<Vec<String>>,
+    /// Habits picked up from the repository's recent commits.
+    pub history: RepoStyle,
+}
+
+const TEXT_TEMPLATE: &str = "Branch: <suggested branch name>
+~~~~~~~~~~
+Subject: <subject (50 chars or less)>
+~~~~~~~~~~
+Body: <body>";
+
+const JSON_TEMPLATE: &str = "{
+  \"branch\": \"<suggested branch name>\",
+  \"subject\": \"<subject (50 chars or less)>\",
+  \"body\": \"<body>\",
+  \"type\": \"<kind of change: feat, fix, docs, style, refactor, perf, test, build, ci or chore>\",
+  \"scope\": \"<part of the code base affected>\" or null,
+  \"breaking\": <true if the change breaks existing users, otherwise false>
+}";
+
+const JSON_NOTE: &str =
+    "Respond with the JSON object only, without any prose or code fences around it.
+";
+
+const INSTRUCTIONS: &str = "Instructions:
+
+You can use the following guidelines to help them write a good commit message:
+Write a concise and informative subject line: The subject line should summarize the change in 50 characters or less. It should be written in the imperative mood (e.g., \"Add feature X\" rather than \"Added feature X\").
+Separate subject from body with a blank line: If you need to provide more context, include a message body after a blank line. This helps separate the summary from the details.
+Explain the \"what\" and \"why\" in the message body: The message body should provide context on why the change was made and any potential implications. Avoid focusing on the \"how\" since that can be deduced from the code itself.
+Use proper grammar, spelling, and punctuation: Good commit messages are well-written and easy to understand. Proper language usage helps convey the meaning effectively.
+Keep line lengths reasonable: Aim for a maximum of 72 characters per line in the message body to ensure readability across different devices and tools.
+Use bullet points or lists for multiple changes: If the commit contains several changes, organize them using bullet points or numbered lists for better readability.
+Avoid generic or ambiguous messages: Commit messages like \"bug fix\" or \"updates\" don't provide enough context. Be specific about the changes you've made.
+Don't include code in the message: The commit message should describe the change, not include the code itself. If the change is too complex to describe succinctly, consider breaking it into smaller commits.
+Proofread before committing: Double-check your commit message for clarity, accuracy, and completeness before submitting it.
+Organize the commit message to include any testing done: If you have tested the code, include the results in the commit message. If you have not tested the code, include a note that you have not tested the code.
+Humor is ok, but don't overdo it. :wink:
+Note: All of the provided code is from a single staged workspace.
+";
+
+const BAD_OUTPUTS: &str = "=====
+Bad Output (explaining the format):
+Let me help you write a good commit message. First...
+=====
+Bad Output (giving advice):
+Here are some ways you could improve your commit message.
+=====
+Bad Output (repeating the template):
+This is a new feature that does something really cool.
+- cool thing 1
+- cool thing 2
+=====
+Bad Output (restating instructions):
+Use proper grammar, spelling, and punctuation: Good commit messages are well-written and easy to understand. Proper language usage helps convey the meaning effectively.
+=====
+Bad Output: Making things up that aren't in the diff.
+";
+
+const CONVENTIONAL_RULES: &str = "This project follows the Conventional Commits specification:
+- The subject has the form `type(scope)!: description`, e.g. `fix(parser): handle empty input`.
+- `(scope)` is optional and names the part of the code base that changed.
+- `!` marks a breaking change. Breaking changes also end the body with a `BREAKING CHANGE: <what breaks and how to migrate>` footer.
+- The description is in the imperative mood, starts with a lowercase letter and has no trailing period.
+";
+
+const CONVENTIONAL_JSON_NOTE: &str = "In the JSON object, `subject` holds only the description; the `type(scope)!: ` prefix is built from the `type`, `scope` and `breaking` fields.
+";
+
+const HISTORY_INTRO: &str = "=====
+This repository's commit messages have habits of their own. Follow them:
+";
+
+const HISTORY_EXAMPLES_INTRO: &str =
+    "Recent commit messages from this repository. Write the Subject and Body in the same style:
+";
+
+const OVERVIEW_INTRO: &str =
+    "Overview of all the changes. The diff after it may only be part of them:
+";
+
+const REDUCE_INTRO: &str = "The staged changes were too large to read at once, so they were split into slices and a commit message was written for each slice.
+Combine the commit messages below into a single commit message that describes the change as a whole.
+Merge duplicate points, keep the most important ones, and suggest one branch name for the whole change.
+";
+
+fn template(format: ResponseFormat) -> &'static str {
+    match format {
+        ResponseFormat::Text => TEXT_TEMPLATE,
+        ResponseFormat::Json => JSON_TEMPLATE,
+    }
+}
+
+fn format_section(format: ResponseFormat, conventions: &Conventions) -> String {
+    let note = match format {
+        ResponseFormat::Text => "",
+        ResponseFormat::Json => JSON_NOTE,
+    };
+    let rules = match &conventions.conventional_types {
+        Some(types) => {
+            let json_note = match format {
+                ResponseFormat::Text => "",
+                ResponseFormat::Json => CONVENTIONAL_JSON_NOTE,
+            };
+            format!(
+                "{}- `type` is one of: {}.\n{}",
+                CONVENTIONAL_RULES,
+                types.join(", "),
+                json_note
+            )
+        }
+        None => String::new(),
+    };
+    format!(
+        "======
+FORMAT
+======
+The commit message should be in the following format:
+==========
+{}
+==========
+END FORMAT
+==========
+{}{}",
+        template(format),
+        note,
+        rules
+    )
+}
+
+/// The observations and examples from the repository's history, if any.
+fn history_section(history: &RepoStyle) -> String {
+    if history.observations.is_empty() && history.examples.is_empty() {
+        return String::new();
+    }
+
+    let mut section = HISTORY_INTRO.to_string();
+    for observation in &history.observations {
+        section.push_str(&format!("- {}\n", observation));
+    }
+    if !history.examples.is_empty() {
+        section.push_str(HISTORY_EXAMPLES_INTRO);
+        for example in &history.examples {
+            section.push_str(&format!("----------\n{}\n", example));
+        }
+        section.push_str("----------\n");
+    }
+    section
+}
+
+/// Sent in front of every slice of the diff: the diffstat `overview` of the
+/// whole change, and the summary of `notable_files` that the diff doesn't show
+/// well.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SlicePreamble {
+    pub overview: String,
+    pub notable_files: String,
+}
+
+fn non_empty_lines(text: &str) -> Vec<&str> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect()
+}
+
+/// The first of `lines` that fit in `max_tokens` tokens, followed by a count
+/// of the lines left out.
+fn fit_lines(lines: &[&str], tokenizer: &CoreBPE, max_tokens: usize) -> String {
+    let count = |text: &str| tokenizer.encode_with_special_tokens(text).len();
+    let note = |left_out: usize| format!("... and {} more lines\n", left_out);
+    let mut text = String::new();
+    let mut used = 0;
+    for (index, line) in lines.iter().enumerate() {
+        let line = format!("{}\n", line);
+        let left_after = lines.len() - index - 1;
+        let reserved = if left_after > 0 {
+            count(&note(left_after))
+        } else {
+            0
+        };
+        if used + count(&line) + reserved > max_tokens {
+            let note = note(lines.len() - index);
+            if used + count(&note) <= max_tokens {
+                text.push_str(&note);
+            }
+            break;
+        }
+        used += count(&line);
+        text.push_str(&line);
+    }
+    text
+}
+
+impl SlicePreamble {
+    /// The preamble in at most `max_tokens` tokens, so a change to thousands
+    /// of files still leaves room for the diff. The notable files get up to
+    /// half of them and the overview the rest.
+    pub fn render(&self, tokenizer: &CoreBPE, max_tokens: usize) -> String {
+        let overview = non_empty_lines(&self.overview);
+        let notable_files = non_empty_lines(&self.notable_files);
+        if overview.is_empty() && notable_files.is_empty() {
+            return String::new();
+        }
+
+        let count = |text: &str| tokenizer.encode_with_special_tokens(text).len();
+        let available = max_tokens.saturating_sub(count(OVERVIEW_INTRO) + count("\n\n=====\n"));
+        let notable_files = fit_lines(&notable_files, tokenizer, available / 2);
+        let overview = fit_lines(
+            &overview,
+            tokenizer,
+            available.saturating_sub(count(&notable_files)),
+        );
+
+        if overview.is_empty() && notable_files.is_empty() {
+            return String::new();
+        }
+        let mut preamble = String::new();
+        if !overview.is_empty() {
+            preamble.push_str(OVERVIEW_INTRO);
+            preamble.push_str(&overview);
+            preamble.push('\n');
+        }
+        if !notable_files.is_empty() {
+            preamble.push_str(&notable_files);
+            preamble.push('\n');
+        }
+        preamble.push_str("=====\n");
+        preamble
+    }
+}
+
+/// The system prompt sent with every slice of the diff.
+pub fn system_message(format: ResponseFormat, conventions: &Conventions) -> String {
+    format!(
+        "You are helping the user write a great commit message.\n{}\n{}{}=====\nGood Output:\n{}\n{}",
+        format_section(format, conventions),
+        INSTRUCTIONS,
+        history_section(&conventions.history),
+        template(format),
+        BAD_OUTPUTS
+    )
+}
+
+/// Sent ahead of the per-slice commit messages when merging them into one.
+pub fn reduce_message(format: ResponseFormat, conventions: &Conventions) -> String {
+    format!("{}{}\n", REDUCE_INTRO, format_section(format, conventions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_prompt_describes_text_format() {
+        let prompt = system_message(ResponseFormat::Text, &Conventions::default());
+        assert!(prompt
+            .starts_with("You are helping the user write a great commit message.\n======\nFORMAT"));
+        assert_eq!(prompt.matches(TEXT_TEMPLATE).count(), 2);
+        assert!(!prompt.contains(JSON_TEMPLATE));
+        assert!(!prompt.contains(CONVENTIONAL_RULES));
+        assert!(!prompt.contains(HISTORY_INTRO));
+    }
+
+    #[test]
+    fn test_json_prompt_describes_json_format() {
+        let prompt = system_message(ResponseFormat::Json, &Conventions::default());
+        assert_eq!(prompt.matches(JSON_TEMPLATE).count(), 2);
+        assert!(!prompt.contains(TEXT_TEMPLATE));
+        assert!(reduce_message(ResponseFormat::Json, &Conventions::default()).contains(JSON_NOTE));
+    }
+
+    #[test]
+    fn test_conventional_rules_list_allowed_types() {
+        let conventions = Conventions {
+            conventional_types: Some(vec!["feat".to_string(), "fix".to_string()]),
+            ..Default::default()
+        };
+        let prompt = system_message(ResponseFormat::Text, &conventions);
+        assert!(prompt.contains(CONVENTIONAL_RULES));
+        assert!(prompt.contains("- `type` is one of: feat, fix.\n"));
+        assert!(!prompt.contains(CONVENTIONAL_JSON_NOTE));
+        assert!(system_message(ResponseFormat::Json, &conventions).contains(CONVENTIONAL_JSON_NOTE));
+    }
+
+    #[test]
+    fn test_history_examples_follow_instructions() {
+        let conventions = Conventions {
+            history: RepoStyle {
+                observations: vec!["Subjects start with a gitmoji.".to_string()],
+                examples: vec!["✨ Add dark mode".to_string()],
+            },
+            ..Default::default()
+        };
+        let prompt = system_message(ResponseFormat::Text, &conventions);
+        let history = prompt.find(HISTORY_INTRO).unwrap();
+        assert!(prompt.find(INSTRUCTIONS).unwrap() < history);
+        assert!(prompt.contains("- Subjects start with a gitmoji.\n"));
+        assert!(prompt.contains("----------\n✨ Add dark mode\n----------\n=====\nGood Output:"));
+    }
+
+    #[test]
+    fn test_slice_preamble() {
+        let tokenizer = tiktoken_rs::cl100k_base().unwrap();
+        let preamble = |overview: &str, notable_files: &str, max_tokens| {
+            SlicePreamble {
+                overview: overview.to_string(),
+                notable_files: notable_files.to_string(),
+            }
+            .render(&tokenizer, max_tokens)
+        };
+        assert_eq!(preamble("", "", 1000), "");
+        let overview = " src/main.rs | 4 ++--\n 1 file changed, 2 insertions(+), 2 deletions(-)\n";
+        assert_eq!(
+            preamble(overview, "Files added:\n- added a.rs\n\n", 1000),
+            format!(
+                "{} src/main.rs | 4 ++--\n 1 file changed, 2 insertions(+), 2 deletions(-)\n\nFiles added:\n- added a.rs\n\n=====\n",
+                OVERVIEW_INTRO
+            )
+        );
+
+        let long: String = (0..150).map(|i| format!(" file{} | 1 +\n", i)).collect();
+        let cut = preamble(&long, "", 200);
+        assert!(tokenizer.encode_with_special_tokens(&cut).len() <= 200);
+        assert!(cut.contains(" file0 | 1 +\n"));
+        assert!(!cut.contains("file149 "));
+        assert!(cut.ends_with(" more lines\n\n=====\n"));
+    }
+}
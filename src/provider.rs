@@ -0,0 +1,263 @@
+//! Completion providers.
+//!
+//! A provider takes a list of chat messages and streams back the model's
+//! response. Everything above this module (slicing the diff, building
+//! prompts, printing the streamed output) only talks to the
+//! [`CompletionProvider`] trait, so new backends can be added without
+//! touching the commit message pipeline.
+
+use std::{fmt, ops::AddAssign, pin::Pin, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::{stream, Stream, StreamExt};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use tiktoken_rs::{cl100k_base, CoreBPE};
+
+use crate::{config::Config, ChatMessage};
+
+pub mod ollama;
+pub mod openai;
+
+pub use ollama::OllamaProvider;
+pub use openai::OpenAiProvider;
+
+/// Default endpoint of a llama.cpp server's OpenAI-compatible API.
+pub const LLAMA_CPP_API_BASE: &str = "http://localhost:8080/v1";
+
+lazy_static! {
+    static ref USAGE_TOKENIZER: CoreBPE = cl100k_base().unwrap();
+}
+
+/// A single piece of a streamed chat completion.
+#[derive(Debug)]
+pub enum CompletionChunk {
+    /// Text generated by the model.
+    Delta(String),
+    /// Token usage, for backends that report it once the completion is done.
+    Usage(TokenUsage),
+}
+
+pub type CompletionStream = Pin<Box<dyn Stream<Item = Result<CompletionChunk>> + Send>>;
+
+/// The shape of answer requested from the model.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    /// The `Branch: ... Subject: ... Body: ...` free-text format.
+    #[default]
+    Text,
+    /// A JSON object with `branch`, `subject`, `body`, `type`, `scope` and
+    /// `breaking` keys. Backends are asked to use their JSON mode as well,
+    /// so the answer is always a valid JSON object.
+    Json,
+}
+
+impl fmt::Display for ResponseFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ResponseFormat::Text => "text",
+            ResponseFormat::Json => "json",
+        })
+    }
+}
+
+/// Sampling parameters sent along with every request.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SamplingOptions {
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u16>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
+impl TokenUsage {
+    pub fn total(&self) -> usize {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+impl AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+#[async_trait]
+pub trait CompletionProvider: Send + Sync {
+    /// The model completions are requested from.
+    fn model(&self) -> &str;
+
+    /// Start a chat completion and stream the response back chunk by chunk.
+    async fn stream_chat(
+        &self,
+        messages: Vec<ChatMessage>,
+        format: ResponseFormat,
+    ) -> Result<CompletionStream>;
+
+    /// Names of the models the backend can serve. Doubles as a cheap check
+    /// that the endpoint is reachable and the credentials are accepted.
+    async fn list_models(&self) -> Result<Vec<String>>;
+
+    /// Estimate token usage for a finished completion.
+    ///
+    /// Used when the backend does not send a [`CompletionChunk::Usage`]
+    /// itself. The estimate uses `cl100k_base` plus a small per-message
+    /// overhead, which is close enough for reporting purposes.
+    fn estimate_usage(&self, messages: &[ChatMessage], completion: &str) -> TokenUsage {
+        let prompt_tokens = messages
+            .iter()
+            .map(|(_, _, content)| USAGE_TOKENIZER.encode_with_special_tokens(content).len() + 4)
+            .sum::<usize>()
+            + 3;
+        let completion_tokens = USAGE_TOKENIZER.encode_with_special_tokens(completion).len();
+
+        TokenUsage {
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+}
+
+/// The backend selected with the `provider` key in `~/.happycommit/config.toml`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ProviderKind {
+    /// The hosted OpenAI API.
+    #[default]
+    OpenAi,
+    /// A local Ollama server, spoken to through `/api/chat`.
+    Ollama,
+    /// A local llama.cpp server, spoken to through its OpenAI-compatible API.
+    LlamaCpp,
+}
+
+impl FromStr for ProviderKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "openai" => Ok(ProviderKind::OpenAi),
+            "ollama" => Ok(ProviderKind::Ollama),
+            "llamacpp" | "llama.cpp" | "llama-cpp" => Ok(ProviderKind::LlamaCpp),
+            other => Err(anyhow!(
+                "Unknown provider \"{}\", expected one of \"openai\", \"ollama\" or \"llamacpp\"",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ProviderKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProviderKind::OpenAi => "openai",
+            ProviderKind::Ollama => "ollama",
+            ProviderKind::LlamaCpp => "llamacpp",
+        })
+    }
+}
+
+impl TryFrom<String> for ProviderKind {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Split a streamed response body into lines. Network chunks don't line up
+/// with lines, so bytes are buffered until a complete one has arrived.
+fn lines(response: reqwest::Response) -> impl Stream<Item = Result<Vec<u8>>> + Send {
+    stream::unfold(
+        (Box::pin(response.bytes_stream()), Vec::new()),
+        |(mut bytes, mut buffer)| async move {
+            loop {
+                if let Some(newline) = buffer.iter().position(|b| *b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=newline).collect();
+                    return Some((Ok(line), (bytes, buffer)));
+                }
+                match bytes.next().await {
+                    Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                    Some(Err(e)) => return Some((Err(e.into()), (bytes, buffer))),
+                    None if buffer.is_empty() => return None,
+                    None => {
+                        let line = std::mem::take(&mut buffer);
+                        return Some((Ok(line), (bytes, buffer)));
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Build the provider selected in the config, with its model, endpoint and
+/// sampling settings applied.
+pub fn from_config(config: &Config) -> Result<Box<dyn CompletionProvider>> {
+    let sampling = SamplingOptions {
+        temperature: config.temperature,
+        max_tokens: config.max_tokens,
+    };
+    let model = config.model.as_deref();
+    let api_base = config.api_base.as_deref();
+
+    // local backends don't need an API key, so only look for one when talking to OpenAI
+    let provider: Box<dyn CompletionProvider> = match config.provider {
+        ProviderKind::OpenAi => {
+            let mut provider = OpenAiProvider::new(config.api_key()?)
+                .with_model(model.unwrap_or(openai::DEFAULT_MODEL))
+                .with_sampling(sampling);
+            if let Some(api_base) = api_base {
+                provider = provider.with_api_base(api_base);
+            }
+            if let Some(api_version) = &config.api_version {
+                let deployment =
+                    config.deployment.as_deref().or(model).ok_or_else(|| {
+                        anyhow!("Azure OpenAI needs a deployment or model to be set")
+                    })?;
+                if api_base.is_none() {
+                    return Err(anyhow!(
+                        "Azure OpenAI needs api_base to be set to your resource, e.g. https://NAME.openai.azure.com"
+                    ));
+                }
+                provider = provider.with_azure(deployment, api_version);
+            }
+            if let Some(organization) = &config.organization {
+                provider = provider.with_org_id(organization);
+            }
+            if let Some(timeout) = config.timeout() {
+                provider = provider.with_timeout(timeout)?;
+            }
+            Box::new(provider)
+        }
+        ProviderKind::Ollama => {
+            let mut provider = OllamaProvider::new(
+                api_base.unwrap_or(ollama::DEFAULT_API_BASE),
+                model.unwrap_or(ollama::DEFAULT_MODEL),
+            )
+            .with_sampling(sampling);
+            if let Some(timeout) = config.timeout() {
+                provider = provider.with_timeout(timeout)?;
+            }
+            Box::new(provider)
+        }
+        ProviderKind::LlamaCpp => {
+            let mut provider = OpenAiProvider::new("")
+                .with_api_base(api_base.unwrap_or(LLAMA_CPP_API_BASE))
+                .with_model(model.unwrap_or("local"))
+                .with_sampling(sampling);
+            if let Some(timeout) = config.timeout() {
+                provider = provider.with_timeout(timeout)?;
+            }
+            Box::new(provider)
+        }
+    };
+
+    Ok(provider)
+}
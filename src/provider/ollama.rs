@@ -0,0 +1,218 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    CompletionChunk, CompletionProvider, CompletionStream, ResponseFormat, SamplingOptions,
+    TokenUsage,
+};
+use crate::ChatMessage;
+
+pub const DEFAULT_API_BASE: &str = "http://localhost:11434";
+pub const DEFAULT_MODEL: &str = "llama2";
+
+/// Chat completions from a local Ollama server, via its native `/api/chat`
+/// endpoint.
+pub struct OllamaProvider {
+    http_client: reqwest::Client,
+    api_base: String,
+    model: String,
+    sampling: SamplingOptions,
+}
+
+impl OllamaProvider {
+    pub fn new(api_base: impl Into<String>, model: impl Into<String>) -> Self {
+        OllamaProvider {
+            http_client: reqwest::Client::new(),
+            api_base: api_base.into().trim_end_matches('/').to_string(),
+            model: model.into(),
+            sampling: SamplingOptions::default(),
+        }
+    }
+
+    pub fn with_sampling(mut self, sampling: SamplingOptions) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Give up on a request, including reading its streamed response, after `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Result<Self> {
+        self.http_client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(self)
+    }
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<Message>,
+    stream: bool,
+    options: Options,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'static str>,
+}
+
+/// Model parameters, named the way Ollama expects them.
+#[derive(Serialize)]
+struct Options {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Message {
+    role: String,
+    content: String,
+}
+
+/// One line of the newline-delimited JSON that `/api/chat` streams back.
+#[derive(Deserialize)]
+struct ChatResponseLine {
+    message: Option<Message>,
+    #[serde(default)]
+    done: bool,
+    prompt_eval_count: Option<usize>,
+    eval_count: Option<usize>,
+    error: Option<String>,
+}
+
+/// The response of `/api/tags`, which lists the locally pulled models.
+#[derive(Deserialize)]
+struct TagsResponse {
+    models: Vec<Tag>,
+}
+
+#[derive(Deserialize)]
+struct Tag {
+    name: String,
+}
+
+fn parse_line(line: &[u8]) -> Vec<Result<CompletionChunk>> {
+    if line.iter().all(u8::is_ascii_whitespace) {
+        return Vec::new();
+    }
+
+    let line: ChatResponseLine = match serde_json::from_slice(line) {
+        Ok(line) => line,
+        Err(e) => return vec![Err(e.into())],
+    };
+
+    if let Some(error) = line.error {
+        return vec![Err(anyhow!("Ollama returned an error: {}", error))];
+    }
+
+    let mut chunks = Vec::new();
+    if let Some(message) = line.message {
+        chunks.push(Ok(CompletionChunk::Delta(message.content)));
+    }
+    if line.done {
+        if let (Some(prompt_tokens), Some(completion_tokens)) =
+            (line.prompt_eval_count, line.eval_count)
+        {
+            chunks.push(Ok(CompletionChunk::Usage(TokenUsage {
+                prompt_tokens,
+                completion_tokens,
+            })));
+        }
+    }
+    chunks
+}
+
+#[async_trait]
+impl CompletionProvider for OllamaProvider {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn stream_chat(
+        &self,
+        messages: Vec<ChatMessage>,
+        format: ResponseFormat,
+    ) -> Result<CompletionStream> {
+        let request = ChatRequest {
+            model: &self.model,
+            messages: messages
+                .into_iter()
+                .map(|(_, role, content)| Message {
+                    role: role.to_string(),
+                    content,
+                })
+                .collect(),
+            stream: true,
+            options: Options {
+                temperature: self.sampling.temperature,
+                num_predict: self.sampling.max_tokens,
+            },
+            format: match format {
+                ResponseFormat::Text => None,
+                ResponseFormat::Json => Some("json"),
+            },
+        };
+
+        let response = self
+            .http_client
+            .post(format!("{}/api/chat", self.api_base))
+            .json(&request)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("Ollama returned {}: {}", status, body));
+        }
+
+        let lines = super::lines(response);
+
+        Ok(Box::pin(
+            lines
+                .map(|line: Result<Vec<u8>>| match line {
+                    Ok(line) => stream::iter(parse_line(&line)),
+                    Err(e) => stream::iter(vec![Err(e)]),
+                })
+                .flatten(),
+        ))
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let response = self
+            .http_client
+            .get(format!("{}/api/tags", self.api_base))
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("Ollama returned {}: {}", status, body));
+        }
+
+        let tags: TagsResponse = response.json().await?;
+        Ok(tags.models.into_iter().map(|tag| tag.name).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line_reports_usage_when_done() {
+        let chunks = parse_line(
+            br#"{"message":{"role":"assistant","content":""},"done":true,"prompt_eval_count":12,"eval_count":34}"#,
+        );
+        assert!(matches!(chunks[0], Ok(CompletionChunk::Delta(ref s)) if s.is_empty()));
+        assert!(matches!(
+            chunks[1],
+            Ok(CompletionChunk::Usage(TokenUsage {
+                prompt_tokens: 12,
+                completion_tokens: 34
+            }))
+        ));
+    }
+}
@@ -0,0 +1,346 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    CompletionChunk, CompletionProvider, CompletionStream, ResponseFormat, SamplingOptions,
+    TokenUsage,
+};
+use crate::ChatMessage;
+
+pub const DEFAULT_API_BASE: &str = "https://api.openai.com/v1";
+pub const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
+
+/// Chat completions from the OpenAI API, or from any server that speaks it.
+pub struct OpenAiProvider {
+    http_client: reqwest::Client,
+    api_base: String,
+    api_key: String,
+    org_id: Option<String>,
+    azure: Option<Azure>,
+    model: String,
+    sampling: SamplingOptions,
+}
+
+/// Where an Azure OpenAI resource serves a model. Azure puts the deployment
+/// and API version in the URL, and takes the key in an `api-key` header.
+struct Azure {
+    deployment: String,
+    api_version: String,
+}
+
+impl OpenAiProvider {
+    pub fn new(api_key: impl Into<String>) -> Self {
+        OpenAiProvider {
+            http_client: reqwest::Client::new(),
+            api_base: DEFAULT_API_BASE.to_string(),
+            api_key: api_key.into(),
+            org_id: None,
+            azure: None,
+            model: DEFAULT_MODEL.to_string(),
+            sampling: SamplingOptions::default(),
+        }
+    }
+
+    /// Point the client at any server that speaks the OpenAI chat API, such
+    /// as a local llama.cpp server or an Azure OpenAI resource.
+    pub fn with_api_base(mut self, api_base: impl Into<String>) -> Self {
+        self.api_base = api_base.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Talk to an Azure OpenAI resource at the API base, sending requests to
+    /// `deployment` with the given `api-version`.
+    pub fn with_azure(
+        mut self,
+        deployment: impl Into<String>,
+        api_version: impl Into<String>,
+    ) -> Self {
+        self.azure = Some(Azure {
+            deployment: deployment.into(),
+            api_version: api_version.into(),
+        });
+        self
+    }
+
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    pub fn with_org_id(mut self, org_id: impl Into<String>) -> Self {
+        self.org_id = Some(org_id.into());
+        self
+    }
+
+    pub fn with_sampling(mut self, sampling: SamplingOptions) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Give up on a request, including reading its streamed response, after `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Result<Self> {
+        self.http_client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(self)
+    }
+
+    fn chat_url(&self) -> String {
+        match &self.azure {
+            Some(azure) => format!(
+                "{}/openai/deployments/{}/chat/completions?api-version={}",
+                self.api_base, azure.deployment, azure.api_version
+            ),
+            None => format!("{}/chat/completions", self.api_base),
+        }
+    }
+
+    fn models_url(&self) -> String {
+        match &self.azure {
+            Some(azure) => format!(
+                "{}/openai/models?api-version={}",
+                self.api_base, azure.api_version
+            ),
+            None => format!("{}/models", self.api_base),
+        }
+    }
+
+    fn chat_request(&self, messages: Vec<ChatMessage>, format: ResponseFormat) -> ChatRequest<'_> {
+        ChatRequest {
+            model: &self.model,
+            messages: messages
+                .into_iter()
+                .map(|(name, role, content)| Message {
+                    role: role.to_string(),
+                    content,
+                    name,
+                })
+                .collect(),
+            stream: true,
+            temperature: self.sampling.temperature,
+            max_tokens: self.sampling.max_tokens,
+            response_format: match format {
+                ResponseFormat::Text => None,
+                ResponseFormat::Json => Some(JsonMode {
+                    r#type: "json_object",
+                }),
+            },
+        }
+    }
+
+    /// Add the credentials, if there are any. Local servers don't need a key.
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let request = match (&self.azure, self.api_key.is_empty()) {
+            (_, true) => request,
+            (Some(_), false) => request.header("api-key", &self.api_key),
+            (None, false) => request.bearer_auth(&self.api_key),
+        };
+        match &self.org_id {
+            Some(org_id) => request.header("OpenAI-Organization", org_id),
+            None => request,
+        }
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let response = self.authorize(request).send().await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("{} returned {}: {}", self.api_base, status, body));
+        }
+        Ok(response)
+    }
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<Message>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<JsonMode>,
+}
+
+/// Asks for JSON mode, which makes the model answer with a single valid
+/// JSON object. llama.cpp's server understands it too.
+#[derive(Serialize)]
+struct JsonMode {
+    r#type: &'static str,
+}
+
+#[derive(Serialize)]
+struct Message {
+    role: String,
+    content: String,
+    name: String,
+}
+
+/// One `data:` event of the server-sent events `/chat/completions` streams back.
+#[derive(Deserialize)]
+struct ChatResponseEvent {
+    #[serde(default)]
+    choices: Vec<Choice>,
+    usage: Option<Usage>,
+    error: Option<ApiError>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    delta: Delta,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    message: String,
+}
+
+/// The response of `/models`.
+#[derive(Deserialize)]
+struct ModelsResponse {
+    data: Vec<Model>,
+}
+
+#[derive(Deserialize)]
+struct Model {
+    id: String,
+}
+
+fn parse_line(line: &[u8]) -> Vec<Result<CompletionChunk>> {
+    // Blank lines separate events, and lines other than `data:` (comments,
+    // `event:`, `id:`) carry nothing we need.
+    let data = match line.trim_ascii().strip_prefix(b"data:") {
+        Some(data) => data.trim_ascii(),
+        None => return Vec::new(),
+    };
+    if data == b"[DONE]" {
+        return Vec::new();
+    }
+
+    let event: ChatResponseEvent = match serde_json::from_slice(data) {
+        Ok(event) => event,
+        Err(e) => return vec![Err(e.into())],
+    };
+
+    if let Some(error) = event.error {
+        return vec![Err(anyhow!("The API returned an error: {}", error.message))];
+    }
+
+    // Azure sends events without choices, e.g. for content filter results
+    let mut chunks: Vec<Result<CompletionChunk>> = event
+        .choices
+        .into_iter()
+        .take(1)
+        .filter_map(|choice| choice.delta.content)
+        .map(|content| Ok(CompletionChunk::Delta(content)))
+        .collect();
+    if let Some(usage) = event.usage {
+        chunks.push(Ok(CompletionChunk::Usage(TokenUsage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        })));
+    }
+    chunks
+}
+
+#[async_trait]
+impl CompletionProvider for OpenAiProvider {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn stream_chat(
+        &self,
+        messages: Vec<ChatMessage>,
+        format: ResponseFormat,
+    ) -> Result<CompletionStream> {
+        let request = self.chat_request(messages, format);
+        let response = self
+            .send(self.http_client.post(self.chat_url()).json(&request))
+            .await?;
+
+        Ok(Box::pin(
+            super::lines(response)
+                .map(|line: Result<Vec<u8>>| match line {
+                    Ok(line) => stream::iter(parse_line(&line)),
+                    Err(e) => stream::iter(vec![Err(e)]),
+                })
+                .flatten(),
+        ))
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let response = self.send(self.http_client.get(self.models_url())).await?;
+        let models: ModelsResponse = response.json().await?;
+        Ok(models.data.into_iter().map(|model| model.id).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_azure_urls() {
+        let provider = OpenAiProvider::new("key")
+            .with_api_base("https://example.openai.azure.com/")
+            .with_azure("commits", "2024-02-01");
+        assert_eq!(
+            provider.chat_url(),
+            "https://example.openai.azure.com/openai/deployments/commits/chat/completions?api-version=2024-02-01"
+        );
+        assert_eq!(
+            provider.models_url(),
+            "https://example.openai.azure.com/openai/models?api-version=2024-02-01"
+        );
+
+        let request = provider
+            .authorize(provider.http_client.get(provider.models_url()))
+            .build()
+            .unwrap();
+        assert_eq!(request.headers()["api-key"], "key");
+        assert!(request.headers().get("authorization").is_none());
+    }
+
+    #[test]
+    fn test_json_mode() {
+        let provider = OpenAiProvider::new("key");
+        let request =
+            |format| serde_json::to_value(provider.chat_request(Vec::new(), format)).unwrap();
+        assert_eq!(
+            request(ResponseFormat::Json)["response_format"],
+            serde_json::json!({"type": "json_object"})
+        );
+        assert!(request(ResponseFormat::Text)
+            .get("response_format")
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let chunks = parse_line(br#"data: {"choices":[{"index":0,"delta":{"content":"Fix"}}]}"#);
+        assert!(matches!(chunks[..], [Ok(CompletionChunk::Delta(ref s))] if s == "Fix"));
+
+        assert!(parse_line(br#"data: {"choices":[],"prompt_filter_results":[]}"#).is_empty());
+        assert!(parse_line(b"data: [DONE]\n").is_empty());
+        assert!(parse_line(b": keep-alive\n").is_empty());
+        assert!(parse_line(b"\n").is_empty());
+    }
+}
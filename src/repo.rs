@@ -0,0 +1,404 @@
+//! Reading the repository through libgit2 instead of the git command line.
+//!
+//! Everything happycommit needs to know before writing a message (the diff,
+//! the current branch, HEAD and the recent log) is read here. Committing and
+//! switching branches are still left to `git`, so hooks, signing and the
+//! user's config apply as usual.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use git2::{
+    Diff, DiffFindOptions, DiffFormat, DiffOptions, DiffStatsFormat, ErrorCode, Index, Repository,
+    Sort, Tree,
+};
+
+use crate::changes::Source;
+
+/// Width of the diffstat, as `git diff --stat` uses outside a terminal.
+const STAT_WIDTH: usize = 80;
+
+#[derive(Debug)]
+pub enum RepoError {
+    /// The current directory isn't in a git work tree.
+    NotARepository,
+    /// The staged changes were asked for, but there aren't any.
+    NothingStaged,
+    /// Other changes were asked for, but there aren't any either.
+    NoChanges,
+    /// HEAD points at a commit rather than a branch.
+    DetachedHead,
+    Git(git2::Error),
+}
+
+impl fmt::Display for RepoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepoError::NotARepository => f.write_str("not inside a git work tree"),
+            RepoError::NothingStaged => f.write_str(
+                "nothing is staged, use `git add` first or pass --all to include unstaged changes",
+            ),
+            RepoError::NoChanges => f.write_str("there are no changes to describe"),
+            RepoError::DetachedHead => f.write_str("HEAD is detached, not on any branch"),
+            RepoError::Git(e) => f.write_str(e.message()),
+        }
+    }
+}
+
+impl std::error::Error for RepoError {}
+
+impl From<git2::Error> for RepoError {
+    fn from(e: git2::Error) -> Self {
+        RepoError::Git(e)
+    }
+}
+
+pub struct Repo {
+    repo: Repository,
+    /// Where diffs are limited to: the directory happycommit was run from,
+    /// relative to the top of the work tree. `None` at the top itself.
+    pathspec: Option<PathBuf>,
+}
+
+impl Repo {
+    /// The repository the current directory is in, with the index
+    /// `GIT_INDEX_FILE` points at, if it's set.
+    pub fn discover() -> Result<Repo, RepoError> {
+        let dir = std::env::current_dir().map_err(|_| RepoError::NotARepository)?;
+        // `git commit -a` and `git commit <paths>` stage into a temporary
+        // index and run the hooks with this pointing at it
+        let index_file = std::env::var_os("GIT_INDEX_FILE").map(|path| dir.join(path));
+        Repo::at(&dir, index_file.as_deref())
+    }
+
+    fn at(dir: &Path, index_file: Option<&Path>) -> Result<Repo, RepoError> {
+        let repo = match Repository::discover(dir) {
+            Ok(repo) => repo,
+            Err(e) if e.code() == ErrorCode::NotFound => return Err(RepoError::NotARepository),
+            Err(e) => return Err(e.into()),
+        };
+        let workdir = repo.workdir().ok_or(RepoError::NotARepository)?;
+        if let Some(path) = index_file {
+            repo.set_index(&mut Index::open(path)?)?;
+        }
+
+        let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let pathspec = canonical(dir)
+            .strip_prefix(canonical(workdir))
+            .ok()
+            .map(Path::to_path_buf)
+            .filter(|prefix| !prefix.as_os_str().is_empty());
+        Ok(Repo { repo, pathspec })
+    }
+
+    /// The top of the work tree.
+    pub fn workdir(&self) -> &Path {
+        // `at` only accepts repositories with a work tree
+        self.repo.workdir().unwrap()
+    }
+
+    /// The name of the checked out branch, even if it has no commits yet.
+    pub fn current_branch(&self) -> Result<String, RepoError> {
+        match self.repo.head() {
+            Ok(head) if head.is_branch() => Ok(head.shorthand().unwrap_or_default().to_string()),
+            Ok(_) => Err(RepoError::DetachedHead),
+            Err(e) if e.code() == ErrorCode::UnbornBranch => {
+                let head = self.repo.find_reference("HEAD")?;
+                let target = head.symbolic_target().unwrap_or_default();
+                Ok(target.trim_start_matches("refs/heads/").to_string())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The tree of the commit HEAD points at, or `None` before the first commit.
+    fn head_tree(&self) -> Result<Option<Tree<'_>>, RepoError> {
+        match self.repo.head() {
+            Ok(head) => Ok(Some(head.peel_to_tree()?)),
+            Err(e) if e.code() == ErrorCode::UnbornBranch => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The last `count` commit messages on the current branch, newest first,
+    /// leaving out merges.
+    pub fn recent_messages(&self, count: usize) -> Result<Vec<String>, RepoError> {
+        let mut walk = self.repo.revwalk()?;
+        walk.set_sorting(Sort::TIME)?;
+        // a repository without commits has no history to learn from
+        if self.head_tree()?.is_none() {
+            return Ok(Vec::new());
+        }
+        walk.push_head()?;
+
+        let mut messages = Vec::new();
+        for oid in walk {
+            if messages.len() == count {
+                break;
+            }
+            let commit = self.repo.find_commit(oid?)?;
+            if commit.parent_count() > 1 {
+                continue;
+            }
+            let message = String::from_utf8_lossy(commit.message_bytes());
+            if !message.trim().is_empty() {
+                messages.push(message.trim().to_string());
+            }
+        }
+        Ok(messages)
+    }
+
+    fn diff(&self, source: &Source) -> Result<Diff<'_>, RepoError> {
+        let repo = &self.repo;
+        let mut options = DiffOptions::new();
+        if let Some(pathspec) = &self.pathspec {
+            options.pathspec(pathspec);
+        }
+
+        let mut diff = match source {
+            Source::Staged => {
+                repo.diff_tree_to_index(self.head_tree()?.as_ref(), None, Some(&mut options))?
+            }
+            // an amended commit replaces the last one, so its message has to cover both
+            Source::Amend => {
+                let head = repo.head()?.peel_to_commit()?;
+                let parent = match head.parent(0) {
+                    Ok(parent) => Some(parent.tree()?),
+                    Err(_) => None,
+                };
+                repo.diff_tree_to_index(parent.as_ref(), None, Some(&mut options))?
+            }
+            Source::All => repo
+                .diff_tree_to_workdir_with_index(self.head_tree()?.as_ref(), Some(&mut options))?,
+            // like `git diff rev...`: what `rev` gained since the branches
+            // forked isn't shown as reverted here
+            Source::Since(rev) => {
+                let since = repo.revparse_single(rev)?.peel_to_commit()?;
+                let head = repo.head()?.peel_to_commit()?;
+                let base = repo.find_commit(repo.merge_base(since.id(), head.id())?)?;
+                repo.diff_tree_to_index(Some(&base.tree()?), None, Some(&mut options))?
+            }
+            // merges are described by what they brought into the first parent
+            Source::Commit(sha) => {
+                let commit = repo.revparse_single(sha)?.peel_to_commit()?;
+                let parent = match commit.parent(0) {
+                    Ok(parent) => Some(parent.tree()?),
+                    Err(_) => None,
+                };
+                repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), Some(&mut options))?
+            }
+        };
+
+        // a moved file is one line in the preamble rather than two whole files
+        diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+
+        // an empty diff would still be sent, and billed
+        if diff.deltas().len() == 0 {
+            return Err(match source {
+                Source::Staged => RepoError::NothingStaged,
+                _ => RepoError::NoChanges,
+            });
+        }
+        Ok(diff)
+    }
+
+    /// The changes for `source` as a unified diff, like `git diff` prints it.
+    pub fn read(&self, source: &Source) -> Result<String, RepoError> {
+        let mut text = String::new();
+        self.diff(source)?
+            .print(DiffFormat::Patch, |_delta, _hunk, line| {
+                if let origin @ ('+' | '-' | ' ') = line.origin() {
+                    text.push(origin);
+                }
+                // files in other encodings shouldn't stop the rest from being described
+                text.push_str(&String::from_utf8_lossy(line.content()));
+                true
+            })?;
+        Ok(text)
+    }
+
+    /// The diffstat for `source`, followed by the files it creates, deletes,
+    /// renames or changes the mode of, like `git diff --stat --summary`.
+    pub fn overview(&self, source: &Source) -> Result<String, RepoError> {
+        let stats = self.diff(source)?.stats()?;
+        let buf = stats.to_buf(
+            DiffStatsFormat::FULL | DiffStatsFormat::INCLUDE_SUMMARY,
+            STAT_WIDTH,
+        )?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    /// The size of the blob with the (possibly abbreviated) hash `blob`, if
+    /// it's in the object database. Unstaged files aren't.
+    pub fn blob_size(&self, blob: &str) -> Option<u64> {
+        let object = self.repo.revparse_single(blob).ok()?;
+        Some(object.peel_to_blob().ok()?.size() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A repository with one commit of `a.txt`, and `src/b.txt` staged.
+    fn repository() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let signature = git2::Signature::now("A Person", "a.person@example.com").unwrap();
+
+        fs::write(dir.path().join("a.txt"), "one\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("a.txt")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "Add a\n\nWith one line.",
+            &tree,
+            &[],
+        )
+        .unwrap();
+
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/b.txt"), "two\n").unwrap();
+        index.add_path(Path::new("src/b.txt")).unwrap();
+        index.write().unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_read_staged_changes() {
+        let dir = repository();
+        let repo = Repo::at(dir.path(), None).unwrap();
+        let diff = repo.read(&Source::Staged).unwrap();
+        assert!(diff.starts_with("diff --git a/src/b.txt b/src/b.txt\nnew file mode 100644\n"));
+        assert!(diff.ends_with("@@ -0,0 +1 @@\n+two\n"));
+
+        let overview = repo.overview(&Source::Staged).unwrap();
+        assert!(overview.contains(" src/b.txt | 1 +\n"));
+        assert!(overview.contains(" create mode 100644 src/b.txt\n"));
+
+        assert_eq!(
+            repo.recent_messages(5).unwrap(),
+            vec!["Add a\n\nWith one line."]
+        );
+        let branch = repo.current_branch().unwrap();
+        assert!(branch == "master" || branch == "main");
+    }
+
+    #[test]
+    fn test_diffs_are_limited_to_the_current_directory() {
+        let dir = repository();
+        fs::write(dir.path().join("a.txt"), "changed\n").unwrap();
+
+        let top = Repo::at(dir.path(), None).unwrap();
+        assert!(top.read(&Source::All).unwrap().contains("+changed\n"));
+
+        let src = Repo::at(&dir.path().join("src"), None).unwrap();
+        assert!(matches!(
+            src.read(&Source::All).map(|diff| diff.contains("a.txt")),
+            Ok(false)
+        ));
+    }
+
+    #[test]
+    fn test_read_another_index() {
+        let dir = repository();
+        let other = dir.path().join(".git/other-index");
+        fs::copy(dir.path().join(".git/index"), &other).unwrap();
+        let mut index = Repository::open(dir.path()).unwrap().index().unwrap();
+        index.remove_path(Path::new("src/b.txt")).unwrap();
+        index.write().unwrap();
+
+        assert!(matches!(
+            Repo::at(dir.path(), None).unwrap().read(&Source::Staged),
+            Err(RepoError::NothingStaged)
+        ));
+        let repo = Repo::at(dir.path(), Some(&other)).unwrap();
+        assert!(repo.read(&Source::Staged).unwrap().contains("+two\n"));
+    }
+
+    #[test]
+    fn test_since_diffs_from_the_merge_base() {
+        let dir = repository();
+        let git = Repository::open(dir.path()).unwrap();
+        let signature = git2::Signature::now("A Person", "a.person@example.com").unwrap();
+        let base = git.head().unwrap().peel_to_commit().unwrap();
+
+        // the current branch commits `src/b.txt`, while upstream adds `c.txt`
+        let tree = git
+            .find_tree(git.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        git.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "Add b",
+            &tree,
+            &[&base],
+        )
+        .unwrap();
+        let mut builder = git.treebuilder(Some(&base.tree().unwrap())).unwrap();
+        builder
+            .insert("c.txt", git.blob(b"three\n").unwrap(), 0o100644)
+            .unwrap();
+        let tree = git.find_tree(builder.write().unwrap()).unwrap();
+        git.commit(
+            Some("refs/heads/upstream"),
+            &signature,
+            &signature,
+            "Add c",
+            &tree,
+            &[&base],
+        )
+        .unwrap();
+
+        let repo = Repo::at(dir.path(), None).unwrap();
+        let diff = repo.read(&Source::Since("upstream".to_string())).unwrap();
+        assert!(diff.contains("+++ b/src/b.txt\n"));
+        assert!(!diff.contains("c.txt"));
+    }
+
+    #[test]
+    fn test_no_commits_yet() {
+        let dir = tempfile::tempdir().unwrap();
+        Repository::init(dir.path()).unwrap();
+        let repo = Repo::at(dir.path(), None).unwrap();
+        assert!(repo.recent_messages(5).unwrap().is_empty());
+        assert!(matches!(
+            repo.read(&Source::Staged),
+            Err(RepoError::NothingStaged)
+        ));
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        let outside = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            Repo::at(outside.path(), None),
+            Err(RepoError::NotARepository)
+        ));
+
+        let dir = repository();
+        let repo = Repo::at(dir.path(), None).unwrap();
+        let head = repo.repo.head().unwrap().target().unwrap();
+        let mut index = repo.repo.index().unwrap();
+        index.remove_path(Path::new("src/b.txt")).unwrap();
+        index.write().unwrap();
+        assert!(matches!(
+            repo.read(&Source::Staged),
+            Err(RepoError::NothingStaged)
+        ));
+        assert!(matches!(repo.read(&Source::All), Err(RepoError::NoChanges)));
+
+        repo.repo.set_head_detached(head).unwrap();
+        assert!(matches!(
+            repo.current_branch(),
+            Err(RepoError::DetachedHead)
+        ));
+    }
+}
@@ -0,0 +1,111 @@
+//! `happycommit setup`: register the `git commit-gpt` alias and store the API key.
+
+use std::{fs, path::Path, process::Command};
+
+use anyhow::{anyhow, Context, Result};
+use toml_edit::{value, Document};
+
+use crate::{cli::confirm, config::Config, provider::ProviderKind};
+
+const API_KEY: &str = "OPENAI_API_KEY";
+
+/// Runs `happycommit commit` from the directory `git commit-gpt` was run in,
+/// rather than from the top of the work tree where git runs shell aliases.
+const ALIAS: &str = "!f() { cd \"${GIT_PREFIX:-.}\" && happycommit commit \"$@\"; }; f";
+
+pub fn run() -> Result<()> {
+    register_alias()?;
+    eprintln!("Registered `git commit-gpt` in your global git config");
+
+    let path = Config::path();
+    create_config(&path)?;
+    eprintln!("Config file is at {}", path.display());
+
+    let config = Config::load()?;
+    if config.provider != ProviderKind::OpenAi {
+        eprintln!("No API key is needed for {}", config.provider);
+        return Ok(());
+    }
+
+    if config.openai_api_key.is_some() && !confirm("An API key is already set. Replace it?")? {
+        return Ok(());
+    }
+    let api_key = rpassword::prompt_password(
+        "Enter your OpenAI API key (get one at https://platform.openai.com/account/api-keys): ",
+    )?;
+    let api_key = api_key.trim();
+    if api_key.is_empty() {
+        eprintln!("No key entered, leaving the config file as it was");
+        return Ok(());
+    }
+
+    let contents = fs::read_to_string(&path)?;
+    fs::write(&path, set_api_key(&contents, api_key)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    eprintln!("API key saved. Happy committing!");
+    Ok(())
+}
+
+fn register_alias() -> Result<()> {
+    let status = Command::new("git")
+        .args(["config", "--global", "alias.commit-gpt", ALIAS])
+        .status()
+        .context("Failed to run git")?;
+    if !status.success() {
+        return Err(anyhow!("git config failed to register the alias"));
+    }
+    Ok(())
+}
+
+/// Create the config file if it doesn't exist yet, and make sure only the
+/// current user can read it, since it may hold an API key.
+fn create_config(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    if !path.exists() {
+        fs::write(path, "").with_context(|| format!("Failed to create {}", path.display()))?;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+/// Set the API key in `contents`, replacing any existing one and keeping
+/// every other setting and comment as it was.
+fn set_api_key(contents: &str, api_key: &str) -> Result<String> {
+    let mut document: Document = contents
+        .parse()
+        .with_context(|| format!("Failed to parse {}", Config::path().display()))?;
+    document[API_KEY] = value(api_key);
+    Ok(document.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_api_key_replaces_existing_key() {
+        let contents = "# my settings\nOPENAI_API_KEY = \"sk-old\"\nmodel = \"gpt-4\"\n";
+        let updated = set_api_key(contents, "sk-new").unwrap();
+        assert_eq!(
+            updated,
+            "# my settings\nOPENAI_API_KEY = \"sk-new\"\nmodel = \"gpt-4\"\n"
+        );
+
+        let config: Config = updated.parse().unwrap();
+        assert_eq!(config.openai_api_key.as_deref(), Some("sk-new"));
+    }
+
+    #[test]
+    fn test_set_api_key_in_empty_file() {
+        assert_eq!(
+            set_api_key("", "sk-new").unwrap(),
+            "OPENAI_API_KEY = \"sk-new\"\n"
+        );
+    }
+}
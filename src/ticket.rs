@@ -0,0 +1,162 @@
+//! Ticket IDs, such as `PROJ-1234`, taken from the name of the current branch.
+//!
+//! Branches like `feature/PROJ-1234-foo` already say which ticket the work is
+//! for, so the ID is carried over to the commit message and to any new
+//! branch happycommit proposes.
+
+use std::fmt;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{commit_message::CommitMessage, conventional::Header};
+
+/// Matches Jira-style IDs when `ticket_pattern` isn't set.
+pub const DEFAULT_PATTERN: &str = r"[A-Z][A-Z0-9]+-[0-9]+";
+
+/// Used instead of the model's suggestion for new branches when a ticket was
+/// found and `branch_template` isn't set.
+pub const BRANCH_TEMPLATE: &str = "{type}/{ticket}-{slug}";
+
+/// Trailer token used with [`Placement::Trailer`].
+const TRAILER: &str = "Refs";
+
+/// Where the ticket IDs go in the commit message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Placement {
+    /// In front of the subject, e.g. `PROJ-1234 Add retries`.
+    #[default]
+    Subject,
+    /// In a `Refs: PROJ-1234` trailer.
+    Trailer,
+    /// Nowhere; only new branch names use them.
+    None,
+}
+
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Placement::Subject => "subject",
+            Placement::Trailer => "trailer",
+            Placement::None => "none",
+        })
+    }
+}
+
+/// The ticket IDs in `branch`, in order and without repeats. When `pattern`
+/// has a capture group, the first group is the ID; otherwise the whole match.
+/// An empty pattern finds nothing.
+pub fn extract(pattern: &Regex, branch: &str) -> Vec<String> {
+    let mut tickets: Vec<String> = Vec::new();
+    for captures in pattern.captures_iter(branch) {
+        let Some(ticket) = captures.get(1).or_else(|| captures.get(0)) else {
+            continue;
+        };
+        if !ticket.is_empty() && !tickets.iter().any(|known| known == ticket.as_str()) {
+            tickets.push(ticket.as_str().to_string());
+        }
+    }
+    tickets
+}
+
+/// Put `tickets` in front of the subject, unless it mentions them already.
+///
+/// A Conventional Commits header has to stay at the start of the subject, so
+/// those messages get the tickets in a trailer instead; see [`trailer`].
+pub fn prefix_subject(message: CommitMessage, tickets: &[String]) -> CommitMessage {
+    let missing: Vec<&str> = tickets
+        .iter()
+        .map(String::as_str)
+        .filter(|ticket| !message.subject.contains(ticket))
+        .collect();
+    if missing.is_empty() || Header::parse(&message.subject).is_ok() {
+        return message;
+    }
+    CommitMessage {
+        subject: format!("{} {}", missing.join(" "), message.subject),
+        ..message
+    }
+}
+
+/// The `Refs:` trailer for `tickets` that `message` should end with, if any.
+pub fn trailer(
+    message: &CommitMessage,
+    tickets: &[String],
+    placement: Placement,
+) -> Option<String> {
+    let trailer = match placement {
+        Placement::Trailer => true,
+        // conventional headers can't take a prefix, see `prefix_subject`
+        Placement::Subject => Header::parse(&message.subject).is_ok(),
+        Placement::None => false,
+    };
+    let missing: Vec<&str> = tickets
+        .iter()
+        .map(String::as_str)
+        .filter(|ticket| !message.subject.contains(ticket) && !message.body.contains(ticket))
+        .collect();
+    (trailer && !missing.is_empty()).then(|| format!("{}: {}", TRAILER, missing.join(", ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        let pattern = Regex::new(DEFAULT_PATTERN).unwrap();
+        assert_eq!(
+            extract(&pattern, "feature/PROJ-1234-foo"),
+            vec!["PROJ-1234"]
+        );
+        assert_eq!(
+            extract(&pattern, "fix/PROJ-1-and-OPS-22-PROJ-1"),
+            vec!["PROJ-1", "OPS-22"]
+        );
+        assert!(extract(&pattern, "main").is_empty());
+        assert!(extract(&Regex::new("").unwrap(), "feature/PROJ-1").is_empty());
+
+        let pattern = Regex::new(r"issue-([0-9]+)").unwrap();
+        assert_eq!(extract(&pattern, "issue-42-crash"), vec!["42"]);
+    }
+
+    #[test]
+    fn test_placement() {
+        let tickets = vec!["PROJ-1234".to_string()];
+        let plain = CommitMessage {
+            subject: "Add retries".to_string(),
+            ..Default::default()
+        };
+        let prefixed = CommitMessage {
+            subject: "PROJ-1234 Add retries".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            prefix_subject(plain.clone(), &tickets).subject,
+            "PROJ-1234 Add retries"
+        );
+        assert_eq!(
+            prefix_subject(prefixed, &tickets).subject,
+            "PROJ-1234 Add retries"
+        );
+        assert_eq!(trailer(&plain, &tickets, Placement::Subject), None);
+        assert_eq!(
+            trailer(&plain, &tickets, Placement::Trailer).as_deref(),
+            Some("Refs: PROJ-1234")
+        );
+
+        let conventional = CommitMessage {
+            subject: "feat: add retries".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            prefix_subject(conventional.clone(), &tickets).subject,
+            "feat: add retries"
+        );
+        assert_eq!(
+            trailer(&conventional, &tickets, Placement::Subject).as_deref(),
+            Some("Refs: PROJ-1234")
+        );
+    }
+}